@@ -142,24 +142,7 @@ Ollama.GenerateEmbeddings("all-minilm", texts, function(err, data)
 
         -- Example: Calculate similarity between first two embeddings (cosine similarity)
         if #data.embeddings >= 2 then
-            local function dot_product(a, b)
-                local sum = 0
-                for i = 1, math.min(#a, #b) do
-                    sum = sum + (a[i] * b[i])
-                end
-                return sum
-            end
-
-            local function magnitude(vec)
-                local sum = 0
-                for i = 1, #vec do
-                    sum = sum + (vec[i] * vec[i])
-                end
-                return math.sqrt(sum)
-            end
-
-            local emb1, emb2 = data.embeddings[1], data.embeddings[2]
-            local similarity = dot_product(emb1, emb2) / (magnitude(emb1) * magnitude(emb2))
+            local similarity = Ollama.CosineSimilarity(data.embeddings[1], data.embeddings[2])
             print("Similarity between first two texts: " .. string.format("%.4f", similarity))
         end
     end
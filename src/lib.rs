@@ -1,6 +1,9 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::time::{Duration, Instant};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use tokio::runtime::Runtime;
@@ -9,48 +12,386 @@ use tokio::runtime::Runtime;
 extern crate gmod;
 
 // Global HTTP client and async runtime
-static mut CLIENT: Option<Client> = None;
-static mut RUNTIME: Option<Runtime> = None;
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+
+// What answered at base_url, told apart so a wrong port or host isn't mistaken for Ollama
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ServerState {
+    // Nothing answered, e.g. connection refused or a timeout
+    Unreachable,
+    // Something answered, but not with the models list Ollama serves at /api/tags
+    NotOllama,
+    // A 401 or 403, the api_key or a proxy's credentials are wrong rather than the url
+    Unauthorized,
+    // A 5xx, from Ollama itself or a proxy in front of it, the url is likely right
+    ServerError,
+    Ok,
+    // Not checked at all, see Ollama.SetEnabled
+    Disabled,
+}
+
+impl ServerState {
+    fn as_str(self) -> &'static str {
+        match self {
+            ServerState::Unreachable => "unreachable",
+            ServerState::NotOllama => "not_ollama",
+            ServerState::Unauthorized => "unauthorized",
+            ServerState::ServerError => "server_error",
+            ServerState::Ok => "ok",
+            ServerState::Disabled => "disabled",
+        }
+    }
+}
+
+// Health check of IsRunning
+async fn probe_server(client: &Client, url: &str) -> ServerState {
+    let response = match client.get(url).timeout(HEALTH_CHECK_TIMEOUT).send().await {
+        Ok(response) => response,
+        Err(_) => return ServerState::Unreachable,
+    };
+
+    let status = response.status().as_u16();
+    classify_probe(status, &response.text().await.unwrap_or_default())
+}
+
+// What answered a /api/tags health check, shared by every check. A 2xx alone isn't enough
+// since any web server can answer one
+fn classify_probe(status: u16, body: &str) -> ServerState {
+    match status {
+        401 | 403 => ServerState::Unauthorized,
+        500..=599 => ServerState::ServerError,
+        200..=299 if is_ollama_tags(body) => ServerState::Ok,
+        _ => ServerState::NotOllama,
+    }
+}
+
+// Whether a /api/tags response body is Ollama's {"models": [...]}
+fn is_ollama_tags(body: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(body)
+        .ok()
+        .is_some_and(|value| value.get("models").is_some_and(serde_json::Value::is_array))
+}
 
 // Cache for IsRunning function
 struct RunningCache {
-    is_running: bool,
+    state: ServerState,
     last_check: Instant,
     first_check_done: bool,
+    // How long the state is trusted, doubled on every failed check up to MAX_CACHE_DURATION
+    interval: Duration,
+    // A background check is in flight, no other one is started meanwhile
+    checking: bool,
+}
+
+impl RunningCache {
+    fn record(&mut self, state: ServerState) {
+        self.interval = if state == ServerState::Ok {
+            CACHE_DURATION
+        } else {
+            (self.interval * 2).min(MAX_CACHE_DURATION)
+        };
+        self.state = state;
+        self.last_check = Instant::now();
+        self.first_check_done = true;
+        self.checking = false;
+    }
 }
 
-static mut RUNNING_CACHE: Option<Arc<Mutex<RunningCache>>> = None;
+static RUNNING_CACHE: Mutex<Option<Arc<Mutex<RunningCache>>>> = Mutex::new(None);
 const CACHE_DURATION: Duration = Duration::from_secs(2);
 
+// Longest wait between checks of a server that keeps failing them
+const MAX_CACHE_DURATION: Duration = Duration::from_secs(60);
+
+// IsRunning fails fast rather than waiting out the request timeout meant for generations,
+// its first check blocks the main thread
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
 // Callback queue for async operations
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum CallbackData {
-    Generate { response: String, model: String },
-    Chat { content: String, role: String, model: String },
+    Generate { response: String, model: String, context: Vec<i32>, thinking: Option<String>, done_reason: Option<String>, metrics: GenerationMetrics },
+    // index counts from 1, delta_ms is the time since the previous chunk (or the request for the first)
+    GenerateToken { token: String, index: u32, delta_ms: f64 },
+    // With output_file the response went to that file instead
+    GenerateDone { response: String, model: String, context: Vec<i32>, thinking: Option<String>, done_reason: Option<String>, metrics: GenerationMetrics, output_file: Option<String> },
+    Chat { content: String, role: String, model: String, tool_calls: Vec<ToolCall>, thinking: Option<String>, done_reason: Option<String>, metrics: GenerationMetrics },
+    ChatDelta { content: String, index: u32, delta_ms: f64 },
+    ChatDone { content: String, role: String, model: String, tool_calls: Vec<ToolCall>, thinking: Option<String>, done_reason: Option<String>, metrics: GenerationMetrics },
     ListModels { models: Vec<ModelInfo> },
-    GetModelInfo { license: String, modelfile: String, parameters: String, template: String },
+    GetModelInfo { license: String, modelfile: String, parameters: String, template: String, details: Option<ModelDetails> },
     IsModelAvailable { is_available: bool },
-    Embeddings { model: String, embeddings: Vec<Vec<f64>> },
+    // With store_handles the vectors go to EMBEDDING_STORE and only their handles reach Lua
+    Embeddings { model: String, embeddings: Vec<Vec<f64>>, include_vectors: bool, store_handles: bool, input_was_batch: bool },
+    EmbedDocument { chunks: Vec<String>, embeddings: Vec<Vec<f64>> },
     GetRunningModels { models: Vec<RunningModelInfo> },
-    Error { message: String },
+    PullProgress { status: String, digest: Option<String>, completed: Option<u64>, total: Option<u64> },
+    PullDone { model: String, status: String },
+    DeleteModel { model: String },
+    CopyModel { source: String, destination: String },
+    UnloadModel { model: String },
+    Preload { model: String, load_duration: Option<u64> },
+    Version { version: String },
+    Ping { latency_ms: f64 },
+    Diagnose { state: ServerState, http_status: Option<u16>, latency_ms: f64, headers: Vec<(String, String)>, error: Option<RequestError> },
+    CountTokens { model: String, tokens: u32 },
+    RawResponse { body: String },
+    DryRun { method: String, url: String, body: String },
+    Cancelled,
+    Error { error: RequestError },
+}
+
+// Why a request failed, kind is one of "connection", "timeout", "http", "model_not_found",
+// "decode", "stream", "rate_limited", "disabled", "not_ollama", "file" or "request" so Lua can
+// tell them apart
+#[derive(Debug, Clone)]
+struct RequestError {
+    kind: &'static str,
+    message: String,
+    http_status: Option<u16>,
+    body: Option<String>,
+}
+
+// Longest part of a non-JSON error body kept in the message, the full body stays in `body`
+const MAX_ERROR_BODY_CHARS: usize = 200;
+
+impl RequestError {
+    fn new(kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            http_status: None,
+            body: None,
+        }
+    }
+
+    // Ollama reports failures as {"error": "..."}, prefer that over the bare status.
+    // Plain text or HTML bodies (e.g. from a proxy) are shown as-is, cut to a sane length
+    fn http(status: reqwest::StatusCode, body: String) -> Self {
+        let ollama_error = serde_json::from_str::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|value| value.get("error").and_then(|error| error.as_str()).map(str::to_string));
+
+        // A model that was never pulled, e.g. 'model "llama2:latest" not found, try pulling it first'
+        let kind = match &ollama_error {
+            Some(error) if status.as_u16() == 404 && error.starts_with("model") && error.contains("not found") => "model_not_found",
+            _ => "http",
+        };
+
+        let message = ollama_error
+            .unwrap_or_else(|| match body.trim() {
+                "" => format!("server responded with {}", status),
+                text if text.chars().count() > MAX_ERROR_BODY_CHARS => {
+                    let text: String = text.chars().take(MAX_ERROR_BODY_CHARS).collect();
+                    format!("server responded with {}: {}...", status, text)
+                },
+                text => format!("server responded with {}: {}", status, text),
+            });
+
+        Self {
+            kind,
+            message,
+            http_status: Some(status.as_u16()),
+            body: Some(body),
+        }
+    }
+
+    fn disabled() -> Self {
+        Self::new("disabled", "ollama disabled")
+    }
+
+    // Something that isn't Ollama answered a health check
+    fn not_ollama(http_status: u16) -> Self {
+        Self {
+            kind: "not_ollama",
+            message: format!("the server responded with HTTP {} but isn't Ollama, check the url and port", http_status),
+            http_status: Some(http_status),
+            body: None,
+        }
+    }
+
+    fn file(e: std::io::Error) -> Self {
+        Self::new("file", format!("failed to write output_file: {}", e))
+    }
+
+    // Failures worth another attempt, Ollama still starting up or a proxy not reaching it yet
+    fn is_transient(&self) -> bool {
+        matches!(self.kind, "connection" | "timeout") || matches!(self.http_status, Some(502..=504))
+    }
+}
+
+impl From<reqwest::Error> for RequestError {
+    fn from(e: reqwest::Error) -> Self {
+        let kind = if e.is_timeout() {
+            "timeout"
+        } else if e.is_connect() {
+            "connection"
+        } else if e.is_decode() {
+            "decode"
+        } else if e.is_status() {
+            "http"
+        } else {
+            "request"
+        };
+
+        Self {
+            kind,
+            message: e.to_string(),
+            http_status: e.status().map(|status| status.as_u16()),
+            body: None,
+        }
+    }
+}
+
+impl From<serde_json::Error> for RequestError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::new("decode", e.to_string())
+    }
+}
+
+impl CallbackData {
+    // Whether this is the last callback of its request, streamed chunks are not
+    fn is_final(&self) -> bool {
+        !matches!(
+            self,
+            CallbackData::GenerateToken { .. } | CallbackData::ChatDelta { .. } | CallbackData::PullProgress { .. }
+        )
+    }
+}
+
+// Timing (in nanoseconds) and token counts Ollama reports with a finished generation
+#[derive(Debug, Default, Clone)]
+struct GenerationMetrics {
+    total_duration: Option<u64>,
+    load_duration: Option<u64>,
+    prompt_eval_count: Option<u32>,
+    prompt_eval_duration: Option<u64>,
+    eval_count: Option<u32>,
+    eval_duration: Option<u64>,
+    // Only passed on with include_metadata
+    created_at: Option<String>,
+}
+
+impl GenerationMetrics {
+    fn from_generate(response: &GenerateResponse) -> Self {
+        Self {
+            total_duration: response.total_duration,
+            load_duration: response.load_duration,
+            prompt_eval_count: response.prompt_eval_count,
+            prompt_eval_duration: response.prompt_eval_duration,
+            eval_count: response.eval_count,
+            eval_duration: response.eval_duration,
+            created_at: Some(response.created_at.clone()).filter(|created_at| !created_at.is_empty()),
+        }
+    }
+
+    fn from_chat(response: &ChatResponse) -> Self {
+        Self {
+            total_duration: response.total_duration,
+            load_duration: response.load_duration,
+            prompt_eval_count: response.prompt_eval_count,
+            prompt_eval_duration: response.prompt_eval_duration,
+            eval_count: response.eval_count,
+            eval_duration: response.eval_duration,
+            created_at: Some(response.created_at.clone()).filter(|created_at| !created_at.is_empty()),
+        }
+    }
 }
 
 struct CallbackResult {
     callback_ref: i32,
+    request_id: u64,
     data: CallbackData,
+    queued_at: Instant,
+}
+
+static CALLBACK_QUEUE: Mutex<VecDeque<CallbackResult>> = Mutex::new(VecDeque::new());
+
+// Signalled whenever the callback queue shrinks, for producers waiting on max_queued_callbacks
+static CALLBACK_QUEUE_SPACE: OnceLock<tokio::sync::Notify> = OnceLock::new();
+
+fn callback_queue_space() -> &'static tokio::sync::Notify {
+    CALLBACK_QUEUE_SPACE.get_or_init(tokio::sync::Notify::new)
+}
+
+// With the block overflow policy, waits for the Think hook to make room in the queue.
+// Awaited by request tasks before they push, so a full queue holds the task and not the
+// runtime worker it runs on
+async fn wait_for_callback_space() {
+    loop {
+        // Created before checking so a drain in between still wakes it up
+        let space = callback_queue_space().notified();
+
+        let config = get_config();
+        let full = config.queue_overflow == QueueOverflow::Block
+            && config.max_queued_callbacks > 0
+            && get_callback_queue().lock().unwrap().len() >= config.max_queued_callbacks;
+        if !full {
+            return;
+        }
+
+        space.await;
+    }
+}
+
+// Requests that lost results to a full queue, true when one of them was the final result.
+// Handled on the next Think since releasing their references needs the Lua state
+static DROPPED_CALLBACKS: Mutex<BTreeMap<u64, bool>> = Mutex::new(BTreeMap::new());
+
+// Ollama.SetEnabled kill switch, kept apart from the config so SetConfig doesn't turn it back on
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+// Unique ids handed out to every async request
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+// A request still waiting for its final callback
+struct PendingRequest {
+    callback_refs: Vec<i32>,
+    task: Option<tokio::task::JoinHandle<()>>,
+    // Caller supplied id passed as the first argument to every callback
+    tag: Option<serde_json::Value>,
+    // History table from NewHistory the chat reply gets appended to, also in callback_refs
+    history_ref: Option<i32>,
+    // Callback receiving the final result, always the last one registered
+    done_ref: Option<i32>,
+    // Aborted with notify_done, only the Cancelled result still gets through
+    cancelled: bool,
 }
 
-static mut CALLBACK_QUEUE: Option<Arc<Mutex<Vec<CallbackResult>>>> = None;
+// Lua references and the running task of each pending request, released once its
+// final callback ran or it got aborted. Only ever touched from the main thread
+static PENDING_REQUESTS: Mutex<BTreeMap<u64, PendingRequest>> = Mutex::new(BTreeMap::new());
 
+// Unset fields are left out rather than sent as null, so Ollama applies its defaults and
+// the extra option can fill them in. Same for ChatRequest
 #[derive(Serialize, Deserialize, Debug)]
 struct GenerateRequest {
     model: String,
     prompt: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suffix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     system: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     template: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     context: Option<Vec<i32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     options: Option<HashMap<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+    // Only sent when set, models decide themselves whether to reason otherwise
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -58,7 +399,11 @@ struct GenerateResponse {
     model: String,
     created_at: String,
     response: String,
+    // Reasoning of thinking models, kept apart from the response
+    thinking: Option<String>,
     done: bool,
+    // "stop" when the model finished, "length" when num_predict cut it off
+    done_reason: Option<String>,
     context: Option<Vec<i32>>,
     total_duration: Option<u64>,
     load_duration: Option<u64>,
@@ -68,18 +413,47 @@ struct GenerateResponse {
     eval_duration: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct ChatMessage {
     role: String,
     content: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thinking: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ToolCall {
+    function: ToolCallFunction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ToolCallFunction {
+    name: String,
+    arguments: serde_json::Value,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ChatRequest {
     model: String,
     messages: Vec<ChatMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     options: Option<HashMap<String, serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keep_alive: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -88,6 +462,7 @@ struct ChatResponse {
     created_at: String,
     message: ChatMessage,
     done: bool,
+    done_reason: Option<String>,
     total_duration: Option<u64>,
     load_duration: Option<u64>,
     prompt_eval_count: Option<u32>,
@@ -97,12 +472,79 @@ struct ChatResponse {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+struct OpenAIChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: Option<bool>,
+    #[serde(flatten)]
+    options: HashMap<String, serde_json::Value>,
+}
+
+// OpenAI leaves content null when the model only calls tools
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIChatMessage {
+    role: String,
+    content: Option<String>,
+    tool_calls: Option<Vec<OpenAIToolCall>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIToolCall {
+    function: OpenAIToolCallFunction,
+}
+
+// Arguments arrive as a JSON encoded string rather than an object
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIToolCallFunction {
+    name: String,
+    arguments: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIChatChoice {
+    message: OpenAIChatMessage,
+    finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIChatResponse {
+    model: String,
+    choices: Vec<OpenAIChatChoice>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIChatDelta {
+    role: Option<String>,
+    content: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIChatStreamChoice {
+    delta: OpenAIChatDelta,
+    finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OpenAIChatStreamChunk {
+    model: String,
+    choices: Vec<OpenAIChatStreamChoice>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ModelDetails {
+    format: Option<String>,
+    family: Option<String>,
+    parameter_size: Option<String>,
+    quantization_level: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct ModelInfo {
     name: String,
     modified_at: String,
     size: u64,
     digest: String,
-    details: Option<serde_json::Value>,
+    details: Option<ModelDetails>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -110,6 +552,11 @@ struct ModelsResponse {
     models: Vec<ModelInfo>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct VersionResponse {
+    version: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct ShowRequest {
     name: String,
@@ -121,7 +568,7 @@ struct ShowResponse {
     modelfile: Option<String>,
     parameters: Option<String>,
     template: Option<String>,
-    details: Option<serde_json::Value>,
+    details: Option<ModelDetails>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -141,7 +588,20 @@ struct EmbedResponse {
     prompt_eval_count: Option<u32>,
 }
 
+// Servers predating /api/embed only have /api/embeddings, one prompt per request
+#[derive(Serialize, Deserialize, Debug)]
+struct LegacyEmbedRequest {
+    model: String,
+    prompt: String,
+    options: Option<HashMap<String, serde_json::Value>>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
+struct LegacyEmbedResponse {
+    embedding: Vec<f64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 struct RunningModelInfo {
     name: String,
     model: String,
@@ -157,10 +617,93 @@ struct RunningModelsResponse {
     models: Vec<RunningModelInfo>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+struct PullRequest {
+    name: String,
+    stream: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PullResponse {
+    status: Option<String>,
+    digest: Option<String>,
+    total: Option<u64>,
+    completed: Option<u64>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DeleteRequest {
+    name: String,
+}
+
+// A generate request with an empty prompt and keep_alive 0 makes Ollama unload the model
+#[derive(Serialize, Deserialize, Debug)]
+struct UnloadRequest {
+    model: String,
+    prompt: String,
+    stream: bool,
+    keep_alive: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CopyRequest {
+    source: String,
+    destination: String,
+}
+
+// How much gets printed to the server console, each level includes the ones before it
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel {
+    Off,
+    Error,
+    Info,
+    Debug,
+}
+
+// What push_callback does once max_queued_callbacks results are waiting
+#[derive(Clone, Copy, PartialEq)]
+enum QueueOverflow {
+    DropOldest,
+    DropNewest,
+    Block,
+}
+
 // Configuration for Ollama connection
 struct OllamaConfig {
     base_url: String,
     timeout: Duration,
+    max_chat_messages: usize,
+    dry_run: bool,
+    warn_invalid_callbacks: bool,
+    openai_compat: bool,
+    keep_alive: Option<serde_json::Value>,
+    options: HashMap<String, serde_json::Value>,
+    default_model: Option<String>,
+    headers: reqwest::header::HeaderMap,
+    max_retries: u32,
+    retry_delay: Duration,
+    max_callbacks_per_frame: usize,
+    // 0 for an unbounded callback queue
+    max_queued_callbacks: usize,
+    queue_overflow: QueueOverflow,
+    max_concurrent_requests: usize,
+    allow_invalid_certs: bool,
+    proxy: Option<reqwest::Proxy>,
+    log_level: LogLevel,
+    max_callback_age: Option<Duration>,
+    // At most this many requests per key over the duration
+    rate_limit: Option<(u32, Duration)>,
+    pool_max_idle_per_host: Option<usize>,
+    pool_idle_timeout: Option<Duration>,
+    user_agent: String,
+    dedupe_requests: bool,
+    default_system: Option<String>,
+    // Requests failing to connect wait for the server to come up instead
+    queue_while_offline: bool,
+    offline_queue_size: usize,
+    offline_queue_timeout: Duration,
+    include_metadata: bool,
 }
 
 impl Default for OllamaConfig {
@@ -168,11 +711,57 @@ impl Default for OllamaConfig {
         Self {
             base_url: "http://localhost:11434".to_string(),
             timeout: Duration::from_secs(30),
+            max_chat_messages: 1000,
+            dry_run: false,
+            warn_invalid_callbacks: true,
+            openai_compat: false,
+            keep_alive: None,
+            options: HashMap::new(),
+            default_model: None,
+            headers: reqwest::header::HeaderMap::new(),
+            max_retries: 0,
+            retry_delay: Duration::from_secs(1),
+            max_callbacks_per_frame: 1000,
+            max_queued_callbacks: 0,
+            queue_overflow: QueueOverflow::DropOldest,
+            max_concurrent_requests: 0,
+            allow_invalid_certs: false,
+            proxy: None,
+            log_level: LogLevel::Off,
+            max_callback_age: None,
+            rate_limit: None,
+            pool_max_idle_per_host: None,
+            pool_idle_timeout: None,
+            user_agent: format!("gm_ollama/{}", env!("CARGO_PKG_VERSION")),
+            dedupe_requests: false,
+            default_system: None,
+            queue_while_offline: false,
+            offline_queue_size: 100,
+            offline_queue_timeout: Duration::from_secs(60),
+            include_metadata: false,
         }
     }
 }
 
-static mut CONFIG: Option<OllamaConfig> = None;
+// Replaced as a whole by SetConfig, tasks keep using the snapshot they started with
+static CONFIG: Mutex<Option<Arc<OllamaConfig>>> = Mutex::new(None);
+
+// Permits for max_concurrent_requests, None when unlimited
+static REQUEST_LIMIT: Mutex<Option<Arc<tokio::sync::Semaphore>>> = Mutex::new(None);
+
+// Token bucket of every rate_limit_key, refilled continuously up to rate_limit.requests
+struct RateBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+static RATE_BUCKETS: Mutex<BTreeMap<String, RateBucket>> = Mutex::new(BTreeMap::new());
+
+// In-flight requests by dedupe key, with the callbacks of identical requests waiting on them
+static SHARED_REQUESTS: Mutex<BTreeMap<String, Vec<(i32, u64)>>> = Mutex::new(BTreeMap::new());
+
+// Number of chunks sent per /api/embed request by EmbedDocument
+const EMBED_BATCH_SIZE: usize = 16;
 
 fn normalize_model_name(model_name: &str) -> String {
     if model_name.contains(':') {
@@ -182,608 +771,3817 @@ fn normalize_model_name(model_name: &str) -> String {
     }
 }
 
-fn get_config() -> &'static OllamaConfig {
-    unsafe {
-        let ptr = std::ptr::addr_of_mut!(CONFIG);
-        (*ptr).get_or_insert_with(OllamaConfig::default)
-    }
+// Ollama names are [host/][namespace/]name[:tag], each part made of letters, digits,
+// '_', '-' and '.' and not starting with a dot or dash
+fn is_valid_model_name(model_name: &str) -> bool {
+    let is_valid_part = |part: &str| {
+        !part.is_empty()
+            && !part.starts_with(['.', '-'])
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    };
+
+    let (path, tag) = match model_name.rsplit_once(':') {
+        Some((path, tag)) => (path, Some(tag)),
+        None => (model_name, None),
+    };
+
+    let parts: Vec<&str> = path.split('/').collect();
+    model_name.len() <= 256
+        && parts.len() <= 3
+        && parts.iter().all(|part| is_valid_part(part))
+        && tag.is_none_or(is_valid_part)
 }
 
-fn get_client() -> &'static Client {
-    unsafe {
-        let ptr = std::ptr::addr_of_mut!(CLIENT);
-        (*ptr).get_or_insert_with(|| {
-            Client::builder()
-                .timeout(get_config().timeout)
-                .build()
-                .expect("Failed to create HTTP client")
-        })
+// Validates a model name coming from Lua before normalizing it, so player input can't
+// produce malformed requests. A leading '!' sends the rest verbatim, without ":latest",
+// for models that don't follow the name:tag convention (e.g. "name@sha256:..." digests),
+// only empty names and control characters are refused then
+unsafe fn check_model_name(lua: gmod::lua::State, model_name: &str) -> String {
+    match model_name.strip_prefix('!') {
+        Some(name) => check_verbatim_model_name(lua, name),
+        None if is_valid_model_name(model_name) => normalize_model_name(model_name),
+        None => lua.error(format!("Invalid model name {:?}", model_name)),
     }
 }
 
-fn get_runtime() -> &'static Runtime {
-    unsafe {
-        let ptr = std::ptr::addr_of_mut!(RUNTIME);
-        (*ptr).get_or_insert_with(|| {
-            Runtime::new().expect("Failed to create async runtime")
-        })
+// Model names sent as they are, for '!' names and OpenAI-compatible backends which don't
+// follow the name:tag convention. Those only need to be non-empty and free of control characters
+unsafe fn check_verbatim_model_name(lua: gmod::lua::State, model_name: &str) -> String {
+    if model_name.is_empty() || model_name.chars().any(char::is_control) {
+        lua.error(format!("Invalid model name {:?}", model_name));
     }
+    model_name.to_string()
 }
 
-fn get_callback_queue() -> Arc<Mutex<Vec<CallbackResult>>> {
-    unsafe {
-        let ptr = std::ptr::addr_of_mut!(CALLBACK_QUEUE);
-        (*ptr).get_or_insert_with(|| {
-            Arc::new(Mutex::new(Vec::new()))
-        }).clone()
-    }
+// Paths written on behalf of Lua stay inside garrysmod/data, like file.Write
+fn is_data_path(path: &str) -> bool {
+    let path = std::path::Path::new(path);
+    path.file_name().is_some()
+        && path.components().all(|component| matches!(component, std::path::Component::Normal(_)))
 }
 
-fn get_running_cache() -> Arc<Mutex<RunningCache>> {
-    unsafe {
-        let ptr = std::ptr::addr_of_mut!(RUNNING_CACHE);
-        (*ptr).get_or_insert_with(|| {
-            Arc::new(Mutex::new(RunningCache {
-                is_running: false,
-                last_check: Instant::now() - CACHE_DURATION, // Force initial check
-                first_check_done: false,
-            }))
-        }).clone()
+// Parses an RFC3339 timestamp (as returned by Ollama) into Unix seconds
+fn parse_rfc3339(timestamp: &str) -> Option<i64> {
+    let bytes = timestamp.as_bytes();
+    if bytes.len() < 20
+        || bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
     }
-}
 
-fn update_running_status_async() {
-    let client = get_client().clone();
-    let config = get_config();
-    let url = format!("{}/api/tags", config.base_url);
-    let runtime = get_runtime();
-    let cache = get_running_cache();
+    let digits = |start: usize, end: usize| -> Option<i64> {
+        let part = timestamp.get(start..end)?;
+        if part.bytes().all(|b| b.is_ascii_digit()) { part.parse().ok() } else { None }
+    };
 
-    runtime.spawn(async move {
-        let is_running = match client.get(&url).send().await {
-            Ok(response) => response.status().is_success(),
-            Err(_) => false,
-        };
+    let (year, month, day) = (digits(0, 4)?, digits(5, 7)?, digits(8, 10)?);
+    let (hour, minute, second) = (digits(11, 13)?, digits(14, 16)?, digits(17, 19)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
 
-        // Update cache
-        if let Ok(mut cache_guard) = cache.lock() {
-            cache_guard.is_running = is_running;
-            cache_guard.last_check = Instant::now();
-            cache_guard.first_check_done = true;
+    // Skip fractional seconds, then read the UTC offset
+    let mut rest = &timestamp[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        rest = fraction.trim_start_matches(|c: char| c.is_ascii_digit());
+    }
+    let offset = match rest.as_bytes() {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), _, _, b':', _, _] => {
+            let offset = digits(timestamp.len() - 5, timestamp.len() - 3)? * 3600
+                + digits(timestamp.len() - 2, timestamp.len())? * 60;
+            if *sign == b'-' { -offset } else { offset }
+        },
+        _ => return None,
+    };
+
+    // Days since the Unix epoch for a proleptic Gregorian date
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second - offset)
+}
+
+// Splits text into chunks of up to chunk_size characters, each one starting
+// overlap characters before the end of the previous chunk
+fn chunk_text(text: &str, chunk_size: usize, overlap: usize) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let step = chunk_size - overlap;
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < chars.len() {
+        let end = (start + chunk_size).min(chars.len());
+        chunks.push(chars[start..end].iter().collect());
+        if end == chars.len() {
+            break;
         }
-    });
+        start += step;
+    }
+
+    chunks
 }
 
-#[lua_function]
-fn ollama_set_config(lua: gmod::lua::State) -> i32 {
-    unsafe {
-        let base_url = lua.check_string(1).to_string();
-        let timeout_secs = if lua.get_top() >= 2 && !lua.is_nil(2) {
-            lua.to_number(2) as u64
-        } else {
-            30
-        };
+// Reads a numeric field from the table at the given index, ignoring nil values
+unsafe fn get_number_field(lua: gmod::lua::State, index: i32, key: gmod::lua::LuaString) -> Option<f64> {
+    lua.get_field(index, key);
+    let value = if lua.is_nil(-1) { None } else { Some(lua.to_number(-1)) };
+    lua.pop();
+    value
+}
 
-        CONFIG = Some(OllamaConfig {
-            base_url,
-            timeout: Duration::from_secs(timeout_secs),
-        });
+// Reads a boolean field from the table at the given index, ignoring nil values
+unsafe fn get_bool_field(lua: gmod::lua::State, index: i32, key: gmod::lua::LuaString) -> Option<bool> {
+    lua.get_field(index, key);
+    let value = if lua.is_nil(-1) { None } else { Some(lua.get_boolean(-1)) };
+    lua.pop();
+    value
+}
 
-        // Reset client to use new config
-        CLIENT = None;
+// Reads a string field from the table at the given index, ignoring nil values
+unsafe fn get_string_field(lua: gmod::lua::State, index: i32, key: gmod::lua::LuaString) -> Option<String> {
+    lua.get_field(index, key);
+    let value = lua.get_string(-1).map(|value| value.to_string());
+    lua.pop();
+    value
+}
 
-        0
+// Optional {base_url = ...} table at index, for the functions taking no other options.
+// Returns the server url of the call and the index of the argument following the table
+unsafe fn read_server_options(lua: gmod::lua::State, index: i32) -> (String, i32) {
+    if !lua.is_table(index) {
+        return (get_config().base_url.clone(), index);
     }
-}
 
-#[lua_function]
-fn ollama_generate(lua: gmod::lua::State) -> i32 {
-    unsafe {
-        let model = normalize_model_name(&lua.check_string(1));
-        let prompt = lua.check_string(2).to_string();
+    let base_url = get_string_field(lua, index, lua_string!("base_url"));
+    (base_url.unwrap_or_else(|| get_config().base_url.clone()), index + 1)
+}
 
-        // Optional system prompt
-        let system = if lua.get_top() >= 3 && !lua.is_nil(3) {
-            Some(lua.check_string(3).to_string())
-        } else {
-            None
-        };
+fn log_enabled(level: LogLevel) -> bool {
+    get_config().log_level >= level
+}
 
-            // Callback function is required
-        if lua.get_top() < 4 || !lua.is_function(4) {
-            lua.error("Callback function is required");
-        }
+fn get_config() -> Arc<OllamaConfig> {
+    CONFIG.lock().unwrap()
+        .get_or_insert_with(|| Arc::new(OllamaConfig::default()))
+        .clone()
+}
 
-        lua.push_value(4);
-        let callback_ref = lua.reference();
+fn get_client() -> Client {
+    CLIENT.lock().unwrap()
+        .get_or_insert_with(|| {
+            let config = get_config();
+            let mut builder = Client::builder()
+                .timeout(config.timeout)
+                .default_headers(config.headers.clone())
+                .user_agent(config.user_agent.clone())
+                // For internal HTTPS endpoints with self-signed certificates
+                .danger_accept_invalid_certs(config.allow_invalid_certs);
+
+            if let Some(proxy) = &config.proxy {
+                builder = builder.proxy(proxy.clone());
+            }
 
-        let request = GenerateRequest {
-            model: model.clone(),
-            prompt: prompt.clone(),
-            stream: Some(false),
-            system,
-            template: None,
-            context: None,
-            options: None,
-        };
+            if let Some(pool_max_idle_per_host) = config.pool_max_idle_per_host {
+                builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+            }
 
-        let client = get_client().clone();
-        let config = get_config();
-        let url = format!("{}/api/generate", config.base_url);
-        let runtime = get_runtime();
-        let queue = get_callback_queue();
+            if let Some(pool_idle_timeout) = config.pool_idle_timeout {
+                builder = builder.pool_idle_timeout(pool_idle_timeout);
+            }
 
-        // Async execution with callback
-        runtime.spawn(async move {
-            let result = async {
-                client.post(&url)
-                    .json(&request)
-                    .send()
-                    .await?
-                    .json::<GenerateResponse>()
-                    .await
-            }.await;
+            builder.build().expect("Failed to create HTTP client")
+        })
+        .clone()
+}
 
-            // Queue the callback result
-            let callback_result = match result {
-                Ok(response) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Generate {
-                        response: response.response,
-                        model: response.model,
-                    },
-                },
-                Err(e) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Error {
-                        message: format!("Error: {}", e),
-                    },
-                },
-            };
+fn get_runtime() -> tokio::runtime::Handle {
+    RUNTIME.lock().unwrap()
+        .get_or_insert_with(|| {
+            Runtime::new().expect("Failed to create async runtime")
+        })
+        .handle()
+        .clone()
+}
 
-            queue.lock().unwrap().push(callback_result);
-        });
+fn get_callback_queue() -> &'static Mutex<VecDeque<CallbackResult>> {
+    &CALLBACK_QUEUE
+}
 
-        0
+fn get_running_cache() -> Arc<Mutex<RunningCache>> {
+    RUNNING_CACHE.lock().unwrap()
+        .get_or_insert_with(|| {
+            Arc::new(Mutex::new(RunningCache {
+                state: ServerState::Unreachable,
+                last_check: Instant::now() - CACHE_DURATION, // Force initial check
+                first_check_done: false,
+                // Doubled by a failing first check, so the next one comes after CACHE_DURATION
+                interval: CACHE_DURATION / 2,
+                checking: false,
+            }))
+        })
+        .clone()
+}
+
+fn get_pending_requests() -> MutexGuard<'static, BTreeMap<u64, PendingRequest>> {
+    PENDING_REQUESTS.lock().unwrap()
+}
+
+// Allocates a request id owning the given callback references
+unsafe fn register_request(lua: gmod::lua::State, callback_refs: Vec<i32>) -> u64 {
+    // Ollama.Shutdown removed the hook, the first request afterwards starts over
+    if CALLBACK_HOOK.lock().unwrap().is_none() {
+        initialize_callback_processor(lua);
     }
+
+    let request_id = NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed);
+    get_pending_requests().insert(request_id, PendingRequest {
+        done_ref: callback_refs.last().copied(),
+        callback_refs,
+        task: None,
+        tag: None,
+        history_ref: None,
+        cancelled: false,
+    });
+    request_id
 }
 
-#[lua_function]
-fn ollama_chat(lua: gmod::lua::State) -> i32 {
-    unsafe {
-        let model = normalize_model_name(&lua.check_string(1));
+// Takes a token from the key's bucket, without one the request is refused with the wait
+// until the next token. While disabled the request is refused as such without taking one
+fn check_rate_limit(key: Option<&str>) -> Result<(), RequestError> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Err(RequestError::disabled());
+    }
 
-        // Check if second argument is a table (messages)
-        if !lua.is_table(2) {
-            lua.error("Second argument must be a table of messages");
-        }
+    let (Some(key), Some((requests, per))) = (key, get_config().rate_limit) else {
+        return Ok(());
+    };
 
-        let mut messages = Vec::new();
-        let len = lua.len(2);
-        for i in 1..=len {
-            lua.raw_geti(2, i as i32); // Get the table entry at index i
+    let capacity = requests as f64;
+    let refill_per_second = capacity / per.as_secs_f64();
+    let now = Instant::now();
 
-            if lua.is_table(-1) {
-                lua.get_field(-1, lua_string!("role"));
-                lua.get_field(-2, lua_string!("content"));
+    let mut buckets = RATE_BUCKETS.lock().unwrap();
 
-                if let (Some(role), Some(content)) = (lua.get_string(-2), lua.get_string(-1)) {
-                    messages.push(ChatMessage {
-                        role: role.to_string(),
-                        content: content.to_string(),
-                    });
-                }
+    // Forget full buckets once there are many keys, they'd start out full anyway
+    if buckets.len() > 256 {
+        buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill_per_second < capacity);
+    }
 
-                lua.pop_n(2); // Pop role and content
-            }
+    let bucket = buckets.entry(key.to_string()).or_insert(RateBucket { tokens: capacity, updated: now });
+    bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * refill_per_second).min(capacity);
+    bucket.updated = now;
 
-            lua.pop(); // Pop table entry
-        }
+    if bucket.tokens < 1.0 {
+        let retry_in = (1.0 - bucket.tokens) / refill_per_second;
+        return Err(RequestError::new("rate_limited", format!("rate limited, retry in {}s", retry_in.ceil())));
+    }
 
-        // Callback function is required
-        if lua.get_top() < 3 || !lua.is_function(3) {
-            lua.error("Callback function is required");
-        }
+    bucket.tokens -= 1.0;
+    Ok(())
+}
 
-        lua.push_value(3);
-        let callback_ref = lua.reference();
+// Chat on a history table keeps a reference to it so the reply can be appended
+unsafe fn track_history(lua: gmod::lua::State, request_id: u64, index: i32) {
+    if !is_history(lua, index) {
+        return;
+    }
 
-        let request = ChatRequest {
-            model: model.clone(),
-            messages,
-            stream: Some(false),
-            options: None,
-        };
+    lua.push_value(index);
+    let history_ref = lua.reference();
+    if let Some(request) = get_pending_requests().get_mut(&request_id) {
+        request.callback_refs.push(history_ref);
+        request.history_ref = Some(history_ref);
+    }
+}
 
-        let client = get_client().clone();
-        let config = get_config();
-        let url = format!("{}/api/chat", config.base_url);
-        let runtime = get_runtime();
-        let queue = get_callback_queue();
+fn tag_request(request_id: u64, tag: Option<serde_json::Value>) {
+    if let Some(request) = get_pending_requests().get_mut(&request_id) {
+        request.tag = tag;
+    }
+}
 
-        // Async execution with callback
-        runtime.spawn(async move {
-            let result = async {
-                client.post(&url)
-                    .json(&request)
-                    .send()
-                    .await?
-                    .json::<ChatResponse>()
-                    .await
-            }.await;
+// Dereferences all callbacks held by a finished request
+unsafe fn release_request(lua: gmod::lua::State, request_id: u64) {
+    if let Some(request) = get_pending_requests().remove(&request_id) {
+        for callback_ref in request.callback_refs {
+            lua.dereference(callback_ref);
+        }
+    }
+}
 
-            // Queue the callback result
-            let callback_result = match result {
-                Ok(response) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Chat {
-                        content: response.message.content,
-                        role: response.message.role,
-                        model: response.model,
-                    },
-                },
-                Err(e) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Error {
-                        message: format!("Error: {}", e),
-                    },
-                },
-            };
+// While disabled requests fail right away with a "disabled" error to their final callback
+fn refuse_if_disabled(request_id: u64) -> bool {
+    if ENABLED.load(Ordering::Relaxed) {
+        return false;
+    }
 
-            queue.lock().unwrap().push(callback_result);
+    if let Some(done_ref) = get_pending_requests().get(&request_id).and_then(|request| request.done_ref) {
+        push_callback(done_ref, request_id, CallbackData::Error {
+            error: RequestError::disabled(),
         });
+    }
+    true
+}
 
-        0
+// Spawns the work of a request on the runtime, keeping its handle so it can be aborted
+fn spawn_task<F>(request_id: u64, future: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    if refuse_if_disabled(request_id) {
+        return;
+    }
+
+    // With a concurrency limit the task waits for a permit before doing any work
+    // and holds it until it's done
+    let request_limit = REQUEST_LIMIT.lock().unwrap().clone();
+    let task = get_runtime().spawn(async move {
+        let _permit = match request_limit {
+            Some(request_limit) => Some(request_limit.acquire_owned().await),
+            None => None,
+        };
+        future.await;
+    });
+
+    if let Some(request) = get_pending_requests().get_mut(&request_id) {
+        request.task = Some(task);
     }
 }
 
-#[lua_function]
-fn ollama_list_models(lua: gmod::lua::State) -> i32 {
-    unsafe {
-        // Callback function is required
-        if lua.get_top() < 1 || !lua.is_function(1) {
-            lua.error("Callback function is required");
+fn push_callback(callback_ref: i32, request_id: u64, data: CallbackData) {
+    let config = get_config();
+    let mut queue = get_callback_queue().lock().unwrap();
+
+    if config.max_queued_callbacks > 0 && queue.len() >= config.max_queued_callbacks {
+        match config.queue_overflow {
+            QueueOverflow::DropOldest => {
+                while queue.len() >= config.max_queued_callbacks {
+                    if let Some(dropped) = queue.pop_front() {
+                        record_dropped_callback(dropped.request_id, dropped.data.is_final());
+                    }
+                }
+            }
+            QueueOverflow::DropNewest => {
+                record_dropped_callback(request_id, data.is_final());
+                return;
+            }
+            // Request tasks already waited in wait_for_callback_space. What still gets here is
+            // pushed over the limit on purpose: results queued on the main thread (refused or
+            // dry run requests) can't wait for the Think hook that runs on that same thread,
+            // and tasks that found room at the same time can overshoot by one result each
+            QueueOverflow::Block => {}
         }
+    }
 
-        lua.push_value(1);
-        let callback_ref = lua.reference();
+    queue.push_back(CallbackResult {
+        callback_ref,
+        request_id,
+        data,
+        queued_at: Instant::now(),
+    });
+}
 
-        let client = get_client().clone();
-        let config = get_config();
-        let url = format!("{}/api/tags", config.base_url);
-        let runtime = get_runtime();
-        let queue = get_callback_queue();
+fn record_dropped_callback(request_id: u64, is_final: bool) {
+    let mut dropped = DROPPED_CALLBACKS.lock().unwrap();
+    let final_dropped = dropped.entry(request_id).or_insert(false);
+    *final_dropped |= is_final;
+}
 
-        // Async execution with callback
-        runtime.spawn(async move {
-            let result = async {
-                client.get(&url)
-                    .send()
-                    .await?
-                    .json::<ModelsResponse>()
-                    .await
-            }.await;
+// Emptying the queue outright, wakes up every producer blocked on it
+fn clear_callback_queue() {
+    if let Ok(mut callbacks) = get_callback_queue().lock() {
+        callbacks.clear();
+    }
+    callback_queue_space().notify_waiters();
+}
 
-                    // Queue the callback result
-            let callback_result = match result {
-                Ok(response) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::ListModels {
-                        models: response.models,
-                    },
-                },
-                Err(e) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Error {
-                        message: format!("Error: {}", e),
-                    },
-                },
-            };
+// Runs the request future on the runtime and queues its result for the callback
+fn spawn_request<F>(callback_ref: i32, request_id: u64, future: F)
+where
+    F: Future<Output = CallbackData> + Send + 'static,
+{
+    spawn_task(request_id, async move {
+        let data = future.await;
+        wait_for_callback_space().await;
+        push_callback(callback_ref, request_id, data);
+    });
+}
 
-            queue.lock().unwrap().push(callback_result);
-        });
+// Identifies a request by where it goes and what it sends, None unless dedupe_requests is on.
+// variant tells apart calls with the same body but different results, e.g. raw_response
+fn dedupe_key(url: &str, request: &impl Serialize, variant: &str) -> Option<String> {
+    if !get_config().dedupe_requests {
+        return None;
+    }
 
-        0
+    let body = serde_json::to_string(request).ok()?;
+    Some(format!("{} {} {}", variant, url, body))
+}
+
+// Tells the requests waiting on a shared one that it was aborted, unless it finished
+struct SharedRequest {
+    key: String,
+    finished: bool,
+}
+
+impl SharedRequest {
+    // Releases the key, returning who waited on it for the result
+    fn finish(&mut self) -> Vec<(i32, u64)> {
+        self.finished = true;
+        SHARED_REQUESTS.lock().unwrap().remove(&self.key).unwrap_or_default()
     }
 }
 
-#[lua_function]
-fn ollama_get_model_info(lua: gmod::lua::State) -> i32 {
-    unsafe {
-        let model_name = normalize_model_name(&lua.check_string(1));
+impl Drop for SharedRequest {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
 
-        // Callback function is required
-        if lua.get_top() < 2 || !lua.is_function(2) {
-            lua.error("Callback function is required");
+        let waiters = SHARED_REQUESTS.lock().unwrap().remove(&self.key).unwrap_or_default();
+        for (callback_ref, request_id) in waiters {
+            push_callback(callback_ref, request_id, CallbackData::Error {
+                error: RequestError::new("request", "the identical request this one was waiting on was aborted"),
+            });
         }
+    }
+}
 
-        lua.push_value(2);
-        let callback_ref = lua.reference();
+// Makes the request the owner of key, or a waiter of the owner already in flight (None)
+fn claim_shared_request(key: String, callback_ref: i32, request_id: u64) -> Option<SharedRequest> {
+    let mut shared_requests = SHARED_REQUESTS.lock().unwrap();
+    if let Some(waiters) = shared_requests.get_mut(&key) {
+        waiters.push((callback_ref, request_id));
+        return None;
+    }
+    shared_requests.insert(key.clone(), Vec::new());
+    Some(SharedRequest { key, finished: false })
+}
 
-        let request = ShowRequest {
-            name: model_name.clone(),
-        };
+// Like spawn_request, but a request identical to one still in flight waits for that one's
+// result instead of being sent again
+fn spawn_shared_request<F>(key: Option<String>, callback_ref: i32, request_id: u64, future: F)
+where
+    F: Future<Output = CallbackData> + Send + 'static,
+{
+    let Some(key) = key else {
+        return spawn_request(callback_ref, request_id, future);
+    };
+
+    if refuse_if_disabled(request_id) {
+        return;
+    }
 
-        let client = get_client().clone();
-        let config = get_config();
-        let url = format!("{}/api/show", config.base_url);
-        let runtime = get_runtime();
-        let queue = get_callback_queue();
+    let Some(mut shared_request) = claim_shared_request(key, callback_ref, request_id) else {
+        return;
+    };
 
-        // Async execution with callback
-        runtime.spawn(async move {
-            let result = async {
-                client.post(&url)
-                    .json(&request)
-                    .send()
-                    .await?
-                    .json::<ShowResponse>()
-                    .await
-            }.await;
+    // The guard moves into the task as is, so aborting it even before its first poll (e.g.
+    // while it waits for a max_concurrent_requests permit) still releases the key
+    spawn_task(request_id, async move {
+        let data = future.await;
 
-            // Queue the callback result
-            let callback_result = match result {
-                Ok(response) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::GetModelInfo {
-                        license: response.license.unwrap_or_else(|| "".to_string()),
-                        modelfile: response.modelfile.unwrap_or_else(|| "".to_string()),
-                        parameters: response.parameters.unwrap_or_else(|| "".to_string()),
-                        template: response.template.unwrap_or_else(|| "".to_string()),
-                    },
-                },
-                Err(e) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Error {
-                        message: format!("Error: {}", e),
-                    },
-                },
-            };
+        wait_for_callback_space().await;
+        for (callback_ref, request_id) in shared_request.finish() {
+            push_callback(callback_ref, request_id, data.clone());
+        }
+        push_callback(callback_ref, request_id, data);
+    });
+}
 
-            queue.lock().unwrap().push(callback_result);
-        });
+// Sends the request, turning non-2xx responses into http errors carrying the status and body
+async fn send_request_once(request: reqwest::RequestBuilder) -> Result<reqwest::Response, RequestError> {
+    let (client, request) = request.build_split();
+    let request = request?;
+    let target = format!("{} {}", request.method(), request.url());
 
-        0
+    if log_enabled(LogLevel::Debug) {
+        let size = request.body().and_then(|body| body.as_bytes()).map_or(0, <[u8]>::len);
+        println!("[gm_ollama] {} sending {} bytes", target, size);
     }
-}
 
-#[lua_function]
-fn ollama_is_model_available(lua: gmod::lua::State) -> i32 {
-    unsafe {
-        let model_name = normalize_model_name(&lua.check_string(1));
+    let start = Instant::now();
+    let response = match client.execute(request).await {
+        Ok(response) => response,
+        Err(error) => {
+            let error = RequestError::from(error);
+            if log_enabled(LogLevel::Info) {
+                println!("[gm_ollama] {} failed after {}ms: {}", target, start.elapsed().as_millis(), error.message);
+            }
+            return Err(error);
+        },
+    };
 
-        // Callback function is required
-        if lua.get_top() < 2 || !lua.is_function(2) {
-            lua.error("Callback function is required");
+    let status = response.status();
+    if log_enabled(LogLevel::Info) {
+        println!("[gm_ollama] {} -> {} in {}ms", target, status, start.elapsed().as_millis());
+    }
+    if log_enabled(LogLevel::Debug) {
+        if let Some(size) = response.content_length() {
+            println!("[gm_ollama] {} received {} bytes", target, size);
         }
+    }
 
-        lua.push_value(2);
-        let callback_ref = lua.reference();
-
-        let client = get_client().clone();
-        let config = get_config();
-        let url = format!("{}/api/tags", config.base_url);
-        let runtime = get_runtime();
-        let queue = get_callback_queue();
+    if status.is_success() {
+        return Ok(response);
+    }
 
-        // Async execution with callback
-        runtime.spawn(async move {
-            let result = async {
-                client.get(&url)
-                    .send()
-                    .await?
-                    .json::<ModelsResponse>()
-                    .await
-            }.await;
+    let body = response.text().await.unwrap_or_default();
+    Err(RequestError::http(status, body))
+}
 
-            // Queue the callback result
-            let callback_result = match result {
-                Ok(response) => {
-                    let is_available = response.models.iter().any(|model| model.name == model_name);
-                    CallbackResult {
-                        callback_ref,
-                        data: CallbackData::IsModelAvailable { is_available },
-                    }
-                },
-                Err(e) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Error {
-                        message: format!("Error: {}", e),
-                    },
-                },
-            };
+// Requests parked by queue_while_offline, and the gate they pass one at a time in the
+// order they were parked
+static OFFLINE_PARKED: AtomicUsize = AtomicUsize::new(0);
+static OFFLINE_GATE: tokio::sync::Semaphore = tokio::sync::Semaphore::const_new(1);
+const OFFLINE_POLL_INTERVAL: Duration = Duration::from_secs(2);
 
-            queue.lock().unwrap().push(callback_result);
-        });
+// Counts a request as parked for as long as it waits, aborted ones included
+struct ParkedRequest;
 
-        0
+impl Drop for ParkedRequest {
+    fn drop(&mut self) {
+        OFFLINE_PARKED.fetch_sub(1, Ordering::Relaxed);
     }
 }
 
-#[lua_function]
-fn ollama_generate_embeddings(lua: gmod::lua::State) -> i32 {
-    unsafe {
-        let model = normalize_model_name(&lua.check_string(1));
+// /api/tags of the server a request URL points to, keeping any path prefix of a proxy
+fn health_check_url(url: &str) -> Option<String> {
+    let index = url.find("/api/").or_else(|| url.find("/v1/"))?;
+    Some(format!("{}/api/tags", &url[..index]))
+}
 
-        // Second argument can be a string or table of strings
-        let input = if lua.is_table(2) {
-            // Handle array of strings
-            let mut inputs = Vec::new();
-            let mut i = 1;
-            loop {
-                lua.push_integer(i as isize);
-                lua.get_table(2);
+// Waits for the server to answer a health check, false when the deadline passed first or
+// offline_queue_size requests are already waiting
+async fn wait_for_server(health_url: &str, deadline: Instant) -> bool {
+    let _parked = ParkedRequest;
+    if OFFLINE_PARKED.fetch_add(1, Ordering::Relaxed) >= get_config().offline_queue_size {
+        return false;
+    }
 
-                if lua.is_nil(-1) {
-                    lua.pop();
-                    break;
-                }
+    // Whoever holds the gate polls for everyone behind it, so a single request checks
+    // the server at a time and they are sent again in order
+    let Ok(Ok(_gate)) = tokio::time::timeout(deadline.saturating_duration_since(Instant::now()), OFFLINE_GATE.acquire()).await else {
+        return false;
+    };
+
+    loop {
+        // Auth failures won't go away by waiting, the request is sent and reports them itself
+        if let Ok(response) = get_client().get(health_url).timeout(HEALTH_CHECK_TIMEOUT).send().await {
+            let status = response.status().as_u16();
+            if matches!(classify_probe(status, &response.text().await.unwrap_or_default()), ServerState::Ok | ServerState::Unauthorized) {
+                return true;
+            }
+        }
 
-                if let Some(text) = lua.get_string(-1) {
-                    inputs.push(text.to_string());
-                }
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return false;
+        }
+        tokio::time::sleep(OFFLINE_POLL_INTERVAL.min(remaining)).await;
+    }
+}
 
-                lua.pop();
-                i += 1;
-            }
-            serde_json::Value::Array(inputs.into_iter().map(serde_json::Value::String).collect())
-        } else {
-            // Handle single string
-            let text = lua.check_string(2).to_string();
-            serde_json::Value::String(text)
+// Sends the request, retrying transient failures with exponential backoff when configured
+async fn send_request(request: reqwest::RequestBuilder) -> Result<reqwest::Response, RequestError> {
+    let config = get_config();
+    let (max_retries, retry_delay) = (config.max_retries, config.retry_delay);
+
+    let mut attempt = 0;
+    let mut offline_deadline = None;
+    loop {
+        // Streamed bodies can't be cloned, those only get a single attempt
+        let Some(attempt_request) = request.try_clone() else {
+            return send_request_once(request).await;
         };
 
-        // Callback function is required
-        if lua.get_top() < 3 || !lua.is_function(3) {
-            lua.error("Callback function is required");
+        match send_request_once(attempt_request).await {
+            Err(error) if attempt < max_retries && error.is_transient() => {
+                tokio::time::sleep(retry_delay.saturating_mul(1 << attempt.min(16))).await;
+                attempt += 1;
+            },
+            // Ollama not up yet, e.g. the server started first. Sent again once it answers,
+            // otherwise the connection error is reported as usual
+            Err(error) if error.kind == "connection" && config.queue_while_offline => {
+                let deadline = *offline_deadline.get_or_insert_with(|| Instant::now() + config.offline_queue_timeout);
+                let health_url = request.try_clone()
+                    .and_then(|request| request.build_split().1.ok())
+                    .and_then(|request| health_check_url(request.url().as_str()));
+
+                match health_url {
+                    Some(health_url) if wait_for_server(&health_url, deadline).await => {},
+                    _ => return Err(error),
+                }
+            },
+            result => return result,
         }
+    }
+}
 
-        lua.push_value(3);
-        let callback_ref = lua.reference();
+// The message of an {"error": "..."} body. Ollama sends those for failures like running out
+// of memory even with a 200 status, and as a line mid-stream instead of a chunk
+fn embedded_error(body: &serde_json::Value) -> Option<String> {
+    body.get("error").and_then(serde_json::Value::as_str).map(str::to_string)
+}
 
-        let request = EmbedRequest {
-            model: model.clone(),
-            input,
-            truncate: Some(true),
-            options: None,
-        };
+// Sends the request and decodes its JSON response, keeping the body around when decoding fails
+async fn send_json<T: serde::de::DeserializeOwned>(request: reqwest::RequestBuilder) -> Result<T, RequestError> {
+    let response = send_request(request).await?;
+    let status = response.status();
+    let body = response.text().await?;
 
-        let client = get_client().clone();
-        let config = get_config();
-        let url = format!("{}/api/embed", config.base_url);
-        let runtime = get_runtime();
-        let queue = get_callback_queue();
+    // Don't let an error reported with a 200 decode into an empty result
+    if body.contains("\"error\"") && embedded_error(&serde_json::from_str(&body).unwrap_or_default()).is_some() {
+        return Err(RequestError::http(status, body));
+    }
 
-        // Async execution with callback
-        runtime.spawn(async move {
-            let result = async {
-                client.post(&url)
-                    .json(&request)
-                    .send()
-                    .await?
-                    .json::<EmbedResponse>()
-                    .await
-            }.await;
+    serde_json::from_str(&body).map_err(|e| RequestError {
+        kind: "decode",
+        message: e.to_string(),
+        http_status: Some(status.as_u16()),
+        body: Some(body),
+    })
+}
 
-            // Queue the callback result
-            let callback_result = match result {
-                Ok(response) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Embeddings {
-                        model: response.model,
-                        embeddings: response.embeddings,
-                    },
-                },
-                Err(e) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Error {
-                        message: format!("Error: {}", e),
-                    },
-                },
-            };
+// Embeds through /api/embed, falling back to the legacy /api/embeddings on servers that
+// don't have it yet. Those answer with a plain "404 page not found" rather than the
+// {"error"} body Ollama sends for an unknown model
+async fn send_embed(client: &Client, base_url: &str, request: &EmbedRequest) -> Result<EmbedResponse, RequestError> {
+    let error = match send_json::<EmbedResponse>(client.post(format!("{}/api/embed", base_url)).json(request)).await {
+        Err(error) if error.http_status == Some(404)
+            && error.body.as_deref().is_some_and(|body| serde_json::from_str::<serde_json::Value>(body).is_err()) => error,
+        result => return result,
+    };
+
+    let prompts = match &request.input {
+        serde_json::Value::String(prompt) => vec![prompt.clone()],
+        serde_json::Value::Array(inputs) => inputs.iter().filter_map(|input| input.as_str().map(str::to_string)).collect(),
+        _ => return Err(error),
+    };
+
+    let mut embeddings = Vec::with_capacity(prompts.len());
+    for prompt in prompts {
+        let legacy_request = LegacyEmbedRequest {
+            model: request.model.clone(),
+            prompt,
+            options: request.options.clone(),
+        };
+        let response = send_json::<LegacyEmbedResponse>(client.post(format!("{}/api/embeddings", base_url)).json(&legacy_request)).await?;
+        embeddings.push(response.embedding);
+    }
 
-            queue.lock().unwrap().push(callback_result);
-        });
+    Ok(EmbedResponse {
+        model: request.model.clone(),
+        embeddings,
+        total_duration: None,
+        load_duration: None,
+        prompt_eval_count: None,
+    })
+}
 
-        0
+// Passes the undecoded response body on, for fields the module doesn't map yet
+async fn send_raw(request: reqwest::RequestBuilder) -> CallbackData {
+    let result = async {
+        let response = send_request(request).await?;
+        Ok::<_, RequestError>(response.text().await?)
+    }.await;
+
+    match result {
+        Ok(body) => CallbackData::RawResponse { body },
+        Err(error) => CallbackData::Error { error },
     }
 }
 
-#[lua_function]
-fn ollama_get_running_models(lua: gmod::lua::State) -> i32 {
-    unsafe {
-        // Callback function is required
-        if lua.get_top() < 1 || !lua.is_function(1) {
-            lua.error("Callback function is required");
-        }
+// Queues the fully built request for the callback instead of sending it
+fn queue_dry_run(callback_ref: i32, request_id: u64, method: &str, url: String, body: String) {
+    if refuse_if_disabled(request_id) {
+        return;
+    }
 
-        lua.push_value(1);
-        let callback_ref = lua.reference();
+    push_callback(callback_ref, request_id, CallbackData::DryRun {
+        method: method.to_string(),
+        url,
+        body,
+    });
+}
 
-        let client = get_client().clone();
-        let config = get_config();
-        let url = format!("{}/api/ps", config.base_url);
-        let runtime = get_runtime();
-        let queue = get_callback_queue();
+// Splits a chunked response body into newline-delimited records, keeping
+// partial lines buffered until the rest of them arrives
+#[derive(Default)]
+struct LineBuffer {
+    buffer: Vec<u8>,
+}
 
-        // Async execution with callback
-        runtime.spawn(async move {
-            let result = async {
-                client.get(&url)
-                    .send()
-                    .await?
-                    .json::<RunningModelsResponse>()
-                    .await
-            }.await;
+impl LineBuffer {
+    fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+    }
 
-            // Queue the callback result
-            let callback_result = match result {
-                Ok(response) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::GetRunningModels {
-                        models: response.models,
-                    },
-                },
-                Err(e) => CallbackResult {
-                    callback_ref,
-                    data: CallbackData::Error {
-                        message: format!("Error: {}", e),
+    // Next complete, non-empty line
+    fn next_line(&mut self) -> Option<String> {
+        while let Some(pos) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            let line = String::from_utf8_lossy(&line).trim().to_string();
+            if !line.is_empty() {
+                return Some(line);
+            }
+        }
+        None
+    }
+
+    // Whatever is left once the body ended without a trailing newline
+    fn finish(&mut self) -> Option<String> {
+        let line = String::from_utf8_lossy(&self.buffer).trim().to_string();
+        self.buffer.clear();
+        if line.is_empty() { None } else { Some(line) }
+    }
+}
+
+fn update_running_status_async() {
+    let client = get_client();
+    let config = get_config();
+    let url = format!("{}/api/tags", config.base_url);
+    let runtime = get_runtime();
+    let cache = get_running_cache();
+
+    runtime.spawn(async move {
+        let state = probe_server(&client, &url).await;
+
+        // Update cache
+        if let Ok(mut cache_guard) = cache.lock() {
+            cache_guard.record(state);
+        }
+    });
+}
+
+#[lua_function]
+fn ollama_set_config(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let base_url = lua.check_string(1).to_string();
+        let timeout_secs = if lua.get_top() >= 2 && !lua.is_nil(2) {
+            lua.to_number(2) as u64
+        } else {
+            30
+        };
+
+        let mut config = OllamaConfig {
+            base_url,
+            timeout: Duration::from_secs(timeout_secs),
+            ..Default::default()
+        };
+
+        // Optional settings table
+        if lua.get_top() >= 3 && lua.is_table(3) {
+            if let Some(max_chat_messages) = get_number_field(lua, 3, lua_string!("max_chat_messages")) {
+                config.max_chat_messages = max_chat_messages as usize;
+            }
+
+            if let Some(dry_run) = get_bool_field(lua, 3, lua_string!("dry_run")) {
+                config.dry_run = dry_run;
+            }
+
+            if let Some(include_metadata) = get_bool_field(lua, 3, lua_string!("include_metadata")) {
+                config.include_metadata = include_metadata;
+            }
+
+            if let Some(dedupe_requests) = get_bool_field(lua, 3, lua_string!("dedupe_requests")) {
+                config.dedupe_requests = dedupe_requests;
+            }
+
+            if let Some(warn_invalid_callbacks) = get_bool_field(lua, 3, lua_string!("warn_invalid_callbacks")) {
+                config.warn_invalid_callbacks = warn_invalid_callbacks;
+            }
+
+            if let Some(openai_compat) = get_bool_field(lua, 3, lua_string!("openai_compat")) {
+                config.openai_compat = openai_compat;
+            }
+
+            if let Some(allow_invalid_certs) = get_bool_field(lua, 3, lua_string!("allow_invalid_certs")) {
+                config.allow_invalid_certs = allow_invalid_certs;
+            }
+
+            if let Some(log_level) = get_string_field(lua, 3, lua_string!("log_level")) {
+                config.log_level = match log_level.as_str() {
+                    "off" => LogLevel::Off,
+                    "error" => LogLevel::Error,
+                    "info" => LogLevel::Info,
+                    "debug" => LogLevel::Debug,
+                    _ => lua.error("log_level must be \"off\", \"error\", \"info\" or \"debug\""),
+                };
+            }
+
+            // Every request goes through this proxy, http(s):// or socks5(h)://
+            if let Some(proxy_url) = get_string_field(lua, 3, lua_string!("proxy_url")) {
+                let supported = ["http://", "https://", "socks5://", "socks5h://"].iter().any(|scheme| proxy_url.starts_with(scheme));
+                match reqwest::Proxy::all(&proxy_url) {
+                    Ok(proxy) if supported => config.proxy = Some(proxy),
+                    _ => lua.error(format!("Invalid proxy_url '{}'", proxy_url)),
+                }
+            }
+
+            // Lets reverse proxies tell the addon's traffic apart
+            if let Some(user_agent) = get_string_field(lua, 3, lua_string!("user_agent")) {
+                if reqwest::header::HeaderValue::from_str(&user_agent).is_err() {
+                    lua.error(format!("Invalid user_agent '{}'", user_agent));
+                }
+                config.user_agent = user_agent;
+            }
+
+            // Connection pool tuning, reqwest's own defaults apply when unset
+            if let Some(pool_max_idle_per_host) = get_number_field(lua, 3, lua_string!("pool_max_idle_per_host")) {
+                config.pool_max_idle_per_host = Some(pool_max_idle_per_host.max(0.0) as usize);
+            }
+
+            if let Some(pool_idle_timeout) = get_number_field(lua, 3, lua_string!("pool_idle_timeout")) {
+                config.pool_idle_timeout = Some(Duration::from_secs_f64(pool_idle_timeout.max(0.0)));
+            }
+
+            if let Some(max_callbacks_per_frame) = get_number_field(lua, 3, lua_string!("max_callbacks_per_frame")) {
+                config.max_callbacks_per_frame = (max_callbacks_per_frame as usize).max(1);
+            }
+
+            // {requests = n, per = seconds} applied to each rate_limit_key separately
+            lua.get_field(3, lua_string!("rate_limit"));
+            if lua.is_table(-1) {
+                let requests = get_number_field(lua, -1, lua_string!("requests")).unwrap_or(0.0);
+                let per = get_number_field(lua, -1, lua_string!("per")).unwrap_or(0.0);
+                if requests < 1.0 || per <= 0.0 {
+                    lua.error("rate_limit needs requests >= 1 and per > 0");
+                }
+                config.rate_limit = Some((requests as u32, Duration::from_secs_f64(per)));
+            }
+            lua.pop();
+
+            if let Some(max_concurrent_requests) = get_number_field(lua, 3, lua_string!("max_concurrent_requests")) {
+                config.max_concurrent_requests = max_concurrent_requests.max(0.0) as usize;
+            }
+
+            if let Some(max_retries) = get_number_field(lua, 3, lua_string!("max_retries")) {
+                config.max_retries = max_retries as u32;
+            }
+
+            if let Some(max_callback_age) = get_number_field(lua, 3, lua_string!("max_callback_age")) {
+                config.max_callback_age = if max_callback_age > 0.0 { Some(Duration::from_secs_f64(max_callback_age)) } else { None };
+            }
+
+            if let Some(retry_delay) = get_number_field(lua, 3, lua_string!("retry_delay")) {
+                config.retry_delay = Duration::from_secs_f64(retry_delay.max(0.0));
+            }
+
+            // Bounds the results waiting for Think, what happens past it depends on queue_overflow
+            if let Some(max_queued_callbacks) = get_number_field(lua, 3, lua_string!("max_queued_callbacks")) {
+                config.max_queued_callbacks = max_queued_callbacks.max(0.0) as usize;
+            }
+
+            if let Some(queue_overflow) = get_string_field(lua, 3, lua_string!("queue_overflow")) {
+                config.queue_overflow = match queue_overflow.as_str() {
+                    "drop_oldest" => QueueOverflow::DropOldest,
+                    "drop_newest" => QueueOverflow::DropNewest,
+                    "block" => QueueOverflow::Block,
+                    _ => lua.error("queue_overflow must be \"drop_oldest\", \"drop_newest\" or \"block\""),
+                };
+            }
+
+            if let Some(queue_while_offline) = get_bool_field(lua, 3, lua_string!("queue_while_offline")) {
+                config.queue_while_offline = queue_while_offline;
+            }
+
+            if let Some(offline_queue_size) = get_number_field(lua, 3, lua_string!("offline_queue_size")) {
+                config.offline_queue_size = offline_queue_size.max(0.0) as usize;
+            }
+
+            if let Some(offline_queue_timeout) = get_number_field(lua, 3, lua_string!("offline_queue_timeout")) {
+                config.offline_queue_timeout = Duration::from_secs_f64(offline_queue_timeout.max(0.0));
+            }
+
+            lua.get_field(3, lua_string!("keep_alive"));
+            if !lua.is_nil(-1) {
+                config.keep_alive = Some(check_keep_alive(lua, lua_to_json(lua, -1)));
+            }
+            lua.pop();
+
+            // Default generation options, per-call options override them key by key
+            lua.get_field(3, lua_string!("options"));
+            if lua.is_table(-1) {
+                if let serde_json::Value::Object(options) = lua_to_json(lua, -1) {
+                    config.options = options.into_iter().collect();
+                    check_num_ctx(lua, &config.options);
+                }
+            }
+            lua.pop();
+
+            lua.get_field(3, lua_string!("default_model"));
+            if let Some(default_model) = lua.get_string(-1) {
+                config.default_model = Some(default_model.to_string());
+            }
+            lua.pop();
+
+            // Baseline persona under every Generate/Chat that doesn't bring its own
+            if let Some(default_system) = get_string_field(lua, 3, lua_string!("default_system")) {
+                config.default_system = Some(default_system);
+            }
+
+            // Extra headers sent with every request, e.g. for a reverse proxy in front of Ollama
+            lua.get_field(3, lua_string!("headers"));
+            if lua.is_table(-1) {
+                if let serde_json::Value::Object(headers) = lua_to_json(lua, -1) {
+                    for (name, value) in headers {
+                        let header_name = reqwest::header::HeaderName::try_from(name.as_str());
+                        let header_value = value.as_str().map(reqwest::header::HeaderValue::from_str);
+                        match (header_name, header_value) {
+                            (Ok(header_name), Some(Ok(header_value))) => {
+                                config.headers.insert(header_name, header_value);
+                            },
+                            _ => lua.error(format!("Invalid header '{}'", name)),
+                        }
+                    }
+                }
+            }
+            lua.pop();
+
+            lua.get_field(3, lua_string!("api_key"));
+            if let Some(api_key) = lua.get_string(-1) {
+                match reqwest::header::HeaderValue::from_str(&format!("Bearer {}", api_key)) {
+                    Ok(mut header_value) => {
+                        header_value.set_sensitive(true);
+                        config.headers.insert(reqwest::header::AUTHORIZATION, header_value);
+                    },
+                    Err(_) => lua.error("Invalid api_key"),
+                }
+            }
+            lua.pop();
+        }
+
+        RATE_BUCKETS.lock().unwrap().clear();
+
+        // Only a new limit replaces the semaphore, otherwise every SetConfig would let a full
+        // set of new requests past the ones still holding permits. Requests already waiting
+        // keep the previous limit
+        if config.max_concurrent_requests != get_config().max_concurrent_requests {
+            *REQUEST_LIMIT.lock().unwrap() = match config.max_concurrent_requests {
+                0 => None,
+                permits => Some(Arc::new(tokio::sync::Semaphore::new(permits))),
+            };
+        }
+
+        *CONFIG.lock().unwrap() = Some(Arc::new(config));
+
+        // Reset client to use new config
+        *CLIENT.lock().unwrap() = None;
+
+        0
+    }
+}
+
+// Reads the messages table at the given index into chat messages
+unsafe fn read_chat_messages(lua: gmod::lua::State, index: i32) -> Vec<ChatMessage> {
+    // Check if the argument is a table (messages)
+    if !lua.is_table(index) {
+        lua.error("Second argument must be a table of messages");
+    }
+
+    let len = lua.len(index);
+    let max_chat_messages = get_config().max_chat_messages;
+    if len as usize > max_chat_messages {
+        lua.error(format!("Too many chat messages ({} > {})", len, max_chat_messages));
+    }
+
+    let mut messages = Vec::new();
+    for i in 1..=len {
+        lua.raw_geti(index, i); // Get the table entry at index i
+
+        if lua.is_table(-1) {
+            lua.get_field(-1, lua_string!("role"));
+            lua.get_field(-2, lua_string!("content"));
+            let message = match (lua.get_string(-2), lua.get_string(-1)) {
+                (Some(role), Some(content)) => Some((role.to_string(), content.to_string())),
+                _ => None,
+            };
+            lua.pop_n(2); // Pop role and content
+
+            if let Some((role, content)) = message {
+                // Optional images for vision models
+                lua.get_field(-1, lua_string!("images"));
+                let images = if lua.is_nil(-1) { None } else { Some(read_images(lua, lua_to_json(lua, -1))) };
+                lua.pop();
+
+                // Tool calls of an assistant message being replayed, as handed to the Chat callback
+                lua.get_field(-1, lua_string!("tool_calls"));
+                let tool_calls = if lua.is_nil(-1) { None } else { Some(read_tool_calls(lua, lua_to_json(lua, -1))) };
+                lua.pop();
+
+                // Name of the tool a {role = "tool"} message answers
+                lua.get_field(-1, lua_string!("tool_name"));
+                let tool_name = lua.get_string(-1).map(|tool_name| tool_name.to_string());
+                lua.pop();
+
+                messages.push(ChatMessage {
+                    role,
+                    content,
+                    images,
+                    tool_calls,
+                    tool_name,
+                    // The model's earlier reasoning isn't sent back
+                    thinking: None,
+                });
+            }
+        }
+
+        lua.pop(); // Pop table entry
+    }
+
+    messages
+}
+
+// Reads tool calls back from the {name, arguments} tables the Chat callback hands out
+unsafe fn read_tool_calls(lua: gmod::lua::State, tool_calls: serde_json::Value) -> Vec<ToolCall> {
+    let serde_json::Value::Array(tool_calls) = tool_calls else {
+        lua.error("tool_calls must be an array of tables");
+    };
+
+    let mut calls = Vec::with_capacity(tool_calls.len());
+    for tool_call in tool_calls {
+        // Also accept the API's own {function = {name, arguments}} shape
+        let mut function = match tool_call {
+            serde_json::Value::Object(mut tool_call) => match tool_call.remove("function") {
+                Some(serde_json::Value::Object(function)) => function,
+                _ => tool_call,
+            },
+            _ => lua.error("tool_calls must be an array of tables"),
+        };
+
+        let name = match function.remove("name") {
+            Some(serde_json::Value::String(name)) => name,
+            _ => lua.error("tool call name must be a string"),
+        };
+
+        calls.push(ToolCall {
+            function: ToolCallFunction {
+                name,
+                arguments: function.remove("arguments").unwrap_or_else(|| serde_json::Value::Object(serde_json::Map::new())),
+            },
+        });
+    }
+    calls
+}
+
+// Standard base64 with padding, as Ollama expects for images
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16
+            | (*chunk.get(1).unwrap_or(&0) as u32) << 8
+            | *chunk.get(2).unwrap_or(&0) as u32;
+
+        encoded.push(ALPHABET[(n >> 18) as usize & 63] as char);
+        encoded.push(ALPHABET[(n >> 12) as usize & 63] as char);
+        encoded.push(if chunk.len() > 1 { ALPHABET[(n >> 6) as usize & 63] as char } else { '=' });
+        encoded.push(if chunk.len() > 2 { ALPHABET[n as usize & 63] as char } else { '=' });
+    }
+    encoded
+}
+
+// Whether text is standard padded base64, like base64_encode produces
+fn is_base64(text: &str) -> bool {
+    let data = text.trim_end_matches('=');
+    !text.is_empty()
+        && text.len().is_multiple_of(4)
+        && text.len() - data.len() <= 2
+        && data.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'+' || b == b'/')
+}
+
+// Resolves an image path inside garrysmod/data, like output_file. Nothing else is ever read
+// so scripts can't send arbitrary files (e.g. cfg/server.cfg) off the machine
+fn resolve_image_path(image: &str) -> Option<std::path::PathBuf> {
+    // Base64 data is far longer than any sensible path
+    if image.len() > 260 || !is_data_path(image) {
+        return None;
+    }
+
+    // Canonicalized so symlinks can't lead out of it either
+    let root = std::env::current_dir().ok()?.join("garrysmod/data").canonicalize().ok()?;
+    let path = root.join(image).canonicalize().ok()?;
+    if path.starts_with(&root) && path.is_file() { Some(path) } else { None }
+}
+
+// Reads a Lua array of images, each either base64 data or the path of an image file
+// relative to garrysmod/data which gets read and encoded here
+unsafe fn read_images(lua: gmod::lua::State, images: serde_json::Value) -> Vec<String> {
+    let images = match images {
+        serde_json::Value::Array(images) => images,
+        _ => lua.error("images must be an array of base64 strings or file paths"),
+    };
+
+    let mut encoded = Vec::with_capacity(images.len());
+    for image in images {
+        let image = match image {
+            serde_json::Value::String(image) if !image.trim().is_empty() => image,
+            _ => lua.error("images must be non-empty strings"),
+        };
+
+        // Anything short with a slash or dot is meant as a path, real base64 images are far
+        // longer. A path that doesn't resolve is reported here and never sent as base64
+        match resolve_image_path(&image) {
+            Some(path) => match std::fs::read(&path) {
+                Ok(bytes) => encoded.push(base64_encode(&bytes)),
+                Err(e) => lua.error(format!("Failed to read image '{}': {}", image, e)),
+            },
+            None if image.len() <= 260 && image.contains(['/', '.']) => {
+                lua.error(format!("Image '{}' is not a file inside garrysmod/data", image))
+            },
+            None if is_base64(&image) => encoded.push(image),
+            None => lua.error("images must be base64 data or paths of files inside garrysmod/data"),
+        }
+    }
+    encoded
+}
+
+// Whole numbers become JSON integers so fields like seed and num_predict deserialize on Ollama's side
+fn number_to_json(number: f64) -> serde_json::Value {
+    if number.fract() == 0.0 && number.abs() < 9007199254740992.0 {
+        serde_json::Value::from(number as i64)
+    } else {
+        serde_json::Number::from_f64(number)
+            .map(serde_json::Value::Number)
+            .unwrap_or(serde_json::Value::Null)
+    }
+}
+
+// Deepest table nesting lua_to_json follows, well past any options or tool schema. Also
+// what stops tables that contain themselves
+const MAX_JSON_DEPTH: usize = 16;
+
+// Where a table sits in what lua_to_json converts, to tell which fields only ever hold lists
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JsonPlace {
+    // The options table (or message, settings...) itself
+    Top,
+    // A JSON schema, as given to format or a tool's parameters
+    Schema,
+    // A schema's properties, named by the user so none of their names mean anything
+    Properties,
+    // anyOf, oneOf or allOf of a schema
+    Schemas,
+    Other,
+}
+
+// Place of the value under key in a table at parent, and whether that value is a list so an
+// empty table there is [] rather than {}
+fn json_child_place(parent: JsonPlace, key: &str) -> (JsonPlace, bool) {
+    match (parent, key) {
+        (JsonPlace::Top, "stop" | "images" | "messages" | "tools") => (JsonPlace::Other, true),
+        (JsonPlace::Top | JsonPlace::Other, "format" | "parameters") => (JsonPlace::Schema, false),
+        (JsonPlace::Schema, "required" | "enum") => (JsonPlace::Other, true),
+        (JsonPlace::Schema, "properties") => (JsonPlace::Properties, false),
+        (JsonPlace::Schema, "items" | "additionalProperties" | "not") => (JsonPlace::Schema, false),
+        (JsonPlace::Schema, "anyOf" | "oneOf" | "allOf") => (JsonPlace::Schemas, true),
+        (JsonPlace::Properties | JsonPlace::Schemas, _) => (JsonPlace::Schema, false),
+        _ => (JsonPlace::Other, false),
+    }
+}
+
+// Converts the Lua value at the given index into JSON. Tables with keys 1..n become
+// arrays, any other table becomes an object. Empty tables are objects too, except for list
+// fields of the options or of a schema, see json_child_place
+unsafe fn lua_to_json(lua: gmod::lua::State, index: i32) -> serde_json::Value {
+    lua_to_json_nested(lua, index, 0, JsonPlace::Top, false)
+}
+
+unsafe fn lua_to_json_nested(lua: gmod::lua::State, index: i32, depth: usize, place: JsonPlace, is_list: bool) -> serde_json::Value {
+    // Relative indices shift while the table is being walked
+    let index = if index < 0 { lua.get_top() + index + 1 } else { index };
+
+    match lua.lua_type(index) {
+        gmod::lua::LUA_TBOOLEAN => serde_json::Value::Bool(lua.get_boolean(index)),
+        gmod::lua::LUA_TNUMBER => number_to_json(lua.to_number(index)),
+        gmod::lua::LUA_TSTRING => serde_json::Value::String(lua.get_string(index).unwrap_or_default().to_string()),
+        gmod::lua::LUA_TTABLE => {
+            if depth >= MAX_JSON_DEPTH {
+                lua.error(format!("Table nested deeper than {} levels, does it contain itself?", MAX_JSON_DEPTH));
+            }
+
+            let mut entries = Vec::new();
+            lua.push_nil();
+            while lua.next(index) != 0 {
+                // Copy the key before reading it, converting it in place would break next
+                lua.push_value(-2);
+                let key = if lua.lua_type(-1) == gmod::lua::LUA_TNUMBER {
+                    (Some(lua.to_number(-1)), lua.get_string(-1).unwrap_or_default().to_string())
+                } else {
+                    (None, lua.get_string(-1).unwrap_or_default().to_string())
+                };
+                lua.pop();
+
+                let (child_place, is_list) = json_child_place(place, &key.1);
+                entries.push((key, lua_to_json_nested(lua, -1, depth + 1, child_place, is_list)));
+                lua.pop(); // Pop the value, keep the key for the next iteration
+            }
+
+            let len = entries.len();
+            let mut positions: Vec<Option<usize>> = entries.iter()
+                .map(|((number, _), _)| number
+                    .filter(|n| n.fract() == 0.0 && *n >= 1.0 && *n <= len as f64)
+                    .map(|n| n as usize - 1))
+                .collect();
+            positions.sort_unstable();
+            positions.dedup();
+
+            if len == 0 && is_list {
+                serde_json::Value::Array(Vec::new())
+            } else if len > 0 && positions.len() == len && positions.iter().all(Option::is_some) {
+                let mut array = vec![serde_json::Value::Null; len];
+                for ((number, _), value) in entries {
+                    if let Some(number) = number {
+                        array[number as usize - 1] = value;
+                    }
+                }
+                serde_json::Value::Array(array)
+            } else {
+                serde_json::Value::Object(entries.into_iter().map(|((_, key), value)| (key, value)).collect())
+            }
+        }
+        _ => serde_json::Value::Null,
+    }
+}
+
+// Reads a Lua array of numbers such as an embedding vector
+unsafe fn read_vector(lua: gmod::lua::State, index: i32) -> Vec<f64> {
+    let index = if index < 0 { lua.get_top() + index + 1 } else { index };
+
+    if !lua.is_table(index) {
+        lua.error("Vector must be a table of numbers");
+    }
+
+    let len = lua.len(index);
+    let mut vector = Vec::with_capacity(len.max(0) as usize);
+    for i in 1..=len {
+        lua.raw_geti(index, i);
+        if lua.lua_type(-1) != gmod::lua::LUA_TNUMBER {
+            lua.error("Vector must be a table of numbers");
+        }
+        vector.push(lua.to_number(-1));
+        lua.pop();
+    }
+
+    vector
+}
+
+// Embedding vectors kept on the Rust side by GenerateEmbeddings with handles = true, by handle.
+// Only touched from the main thread, they live until FreeEmbedding or Shutdown
+static EMBEDDING_STORE: Mutex<BTreeMap<u64, Vec<f64>>> = Mutex::new(BTreeMap::new());
+static NEXT_EMBEDDING_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+fn store_embeddings(embeddings: Vec<Vec<f64>>) -> Vec<u64> {
+    let mut store = EMBEDDING_STORE.lock().unwrap();
+    embeddings.into_iter()
+        .map(|embedding| {
+            let handle = NEXT_EMBEDDING_HANDLE.fetch_add(1, Ordering::Relaxed);
+            store.insert(handle, embedding);
+            handle
+        })
+        .collect()
+}
+
+// Vectors without any magnitude have no direction to compare, they score 0
+fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let mut dot = 0.0;
+    let mut norm_a = 0.0;
+    let mut norm_b = 0.0;
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+// keep_alive is either a duration string like "30m" or a number of seconds,
+// 0 unloads the model right away and -1 keeps it loaded forever
+unsafe fn check_keep_alive(lua: gmod::lua::State, value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::String(_) | serde_json::Value::Number(_) => value,
+        _ => lua.error("keep_alive must be a duration string or a number of seconds"),
+    }
+}
+
+// Per-call settings from the options table, keys that are request fields
+// rather than model options are pulled out of it
+#[derive(Default)]
+struct CallOptions {
+    options: Option<HashMap<String, serde_json::Value>>,
+    keep_alive: Option<serde_json::Value>,
+    context: Option<Vec<i32>>,
+    system: Option<String>,
+    format: Option<serde_json::Value>,
+    images: Option<Vec<String>>,
+    tools: Option<Vec<serde_json::Value>>,
+    raw: Option<bool>,
+    template: Option<String>,
+    suffix: Option<String>,
+    base_url: Option<String>,
+    raw_response: Option<bool>,
+    id: Option<serde_json::Value>,
+    rate_limit_key: Option<String>,
+    think: Option<bool>,
+    output_file: Option<String>,
+    extra: Option<serde_json::Map<String, serde_json::Value>>,
+}
+
+// num_ctx sets the context window in tokens, long conversations need more than the
+// model's default (often 2048) or their beginning gets cut off
+unsafe fn check_num_ctx(lua: gmod::lua::State, options: &HashMap<String, serde_json::Value>) {
+    if let Some(num_ctx) = options.get("num_ctx") {
+        if matches!(num_ctx.as_u64(), None | Some(0)) {
+            lua.error("num_ctx must be a positive integer");
+        }
+    }
+}
+
+// Reads an optional table of Ollama generation options (temperature, top_p, seed, ...)
+unsafe fn read_options(lua: gmod::lua::State, index: i32) -> CallOptions {
+    if lua.is_none(index) || lua.is_nil(index) {
+        return CallOptions::default();
+    }
+
+    // Presets only come through the table's preset key. A bare name would read as the system
+    // prompt in the Generate and Chat argument lists, which take a string right before this
+    if !lua.is_table(index) {
+        lua.error("Options must be a table, use {preset = name} for a preset");
+    }
+    let mut options = read_preset_table(lua, index);
+
+    let keep_alive = options.remove("keep_alive").map(|value| check_keep_alive(lua, value));
+    check_num_ctx(lua, &options);
+
+    // Ollama only takes an array of stop sequences, a lone string is wrapped into one
+    // and an empty table (which converts to an object) is dropped
+    match options.remove("stop") {
+        Some(serde_json::Value::String(stop)) => {
+            options.insert("stop".to_string(), serde_json::Value::Array(vec![serde_json::Value::String(stop)]));
+        },
+        Some(serde_json::Value::Array(stop)) if stop.iter().all(serde_json::Value::is_string) => {
+            options.insert("stop".to_string(), serde_json::Value::Array(stop));
+        },
+        Some(serde_json::Value::Object(stop)) if stop.is_empty() => {},
+        Some(_) => lua.error("stop must be a string or an array of strings"),
+        None => {},
+    }
+
+    // Context tokens returned by a previous Generate, an empty table converts to an object
+    let context = match options.remove("context") {
+        None => None,
+        Some(serde_json::Value::Object(object)) if object.is_empty() => None,
+        Some(value) => match serde_json::from_value::<Vec<i32>>(value) {
+            Ok(context) => Some(context),
+            Err(_) => lua.error("context must be an array of integers"),
+        },
+    };
+
+    let system = match options.remove("system") {
+        None => None,
+        Some(serde_json::Value::String(system)) => Some(system),
+        Some(_) => lua.error("system must be a string"),
+    };
+
+    // Either "json" or a JSON schema the output has to follow
+    let format = match options.remove("format") {
+        None => None,
+        Some(format @ (serde_json::Value::String(_) | serde_json::Value::Object(_))) => Some(format),
+        Some(_) => lua.error("format must be \"json\" or a JSON schema table"),
+    };
+
+    let images = options.remove("images").map(|images| read_images(lua, images));
+
+    // Function schemas the model may call, {name, description, parameters} entries
+    // are wrapped into the {type = "function", function = ...} shape the API expects
+    let tools = match options.remove("tools") {
+        None => None,
+        Some(serde_json::Value::Array(tools)) => Some(tools.into_iter().map(|tool| match tool {
+            serde_json::Value::Object(tool) if !tool.contains_key("type") => serde_json::json!({
+                "type": "function",
+                "function": tool,
+            }),
+            serde_json::Value::Object(_) => tool,
+            _ => lua.error("tools must be an array of tables"),
+        }).collect()),
+        Some(_) => lua.error("tools must be an array of tables"),
+    };
+
+    // Raw prompts bypass the model's template, a custom template replaces it
+    let raw = match options.remove("raw") {
+        None => None,
+        Some(serde_json::Value::Bool(raw)) => Some(raw),
+        Some(_) => lua.error("raw must be a boolean"),
+    };
+
+    let template = match options.remove("template") {
+        None => None,
+        Some(serde_json::Value::String(template)) => Some(template),
+        Some(_) => lua.error("template must be a string"),
+    };
+
+    // Text after the gap a fill-in-the-middle model completes between prompt and suffix
+    let suffix = match options.remove("suffix") {
+        None => None,
+        Some(serde_json::Value::String(suffix)) => Some(suffix),
+        Some(_) => lua.error("suffix must be a string"),
+    };
+
+    // GenerateStream writes the response to this file under garrysmod/data as it arrives
+    let output_file = match options.remove("output_file") {
+        None => None,
+        Some(serde_json::Value::String(output_file)) if is_data_path(&output_file) => Some(output_file),
+        Some(_) => lua.error("output_file must be a relative path inside the data folder"),
+    };
+
+    // Sends just this call to another server, e.g. a separate box for embeddings
+    let base_url = match options.remove("base_url") {
+        None => None,
+        Some(serde_json::Value::String(base_url)) => Some(base_url),
+        Some(_) => lua.error("base_url must be a string"),
+    };
+
+    // Skips decoding and hands the response body to the callback as-is
+    let raw_response = match options.remove("raw_response") {
+        None => None,
+        Some(serde_json::Value::Bool(raw_response)) => Some(raw_response),
+        Some(_) => lua.error("raw_response must be a boolean"),
+    };
+
+    // Opaque id handed back to the callbacks, to route results without a closure per call
+    let id = match options.remove("id") {
+        None => None,
+        Some(id @ (serde_json::Value::String(_) | serde_json::Value::Number(_))) => Some(id),
+        Some(_) => lua.error("id must be a string or a number"),
+    };
+
+    // Who the request counts against for rate_limit, e.g. a player's SteamID
+    let rate_limit_key = match options.remove("rate_limit_key") {
+        None => None,
+        Some(serde_json::Value::String(key)) => Some(key),
+        Some(_) => lua.error("rate_limit_key must be a string"),
+    };
+
+    // Turns the reasoning of thinking models (deepseek-r1, qwen3, ...) on or off
+    let think = match options.remove("think") {
+        None => None,
+        Some(serde_json::Value::Bool(think)) => Some(think),
+        Some(_) => lua.error("think must be a boolean"),
+    };
+
+    // Top-level request fields the module doesn't know about yet, sent as-is
+    let extra = match options.remove("extra") {
+        None => None,
+        Some(serde_json::Value::Object(extra)) => Some(extra),
+        Some(_) => lua.error("extra must be a table of request fields"),
+    };
+
+    CallOptions {
+        options: if options.is_empty() { None } else { Some(options) },
+        keep_alive,
+        context,
+        system,
+        format,
+        images,
+        tools,
+        raw,
+        template,
+        suffix,
+        base_url,
+        raw_response,
+        id,
+        rate_limit_key,
+        think,
+        output_file,
+        extra,
+    }
+}
+
+// The request body with the extra option's fields added at the top level, fields the
+// request sets itself win over them
+fn with_extra_fields(request: impl Serialize, extra: Option<serde_json::Map<String, serde_json::Value>>) -> serde_json::Value {
+    let mut body = serde_json::to_value(request).unwrap_or_default();
+    if let (serde_json::Value::Object(body), Some(extra)) = (&mut body, extra) {
+        for (key, value) in extra {
+            body.entry(key).or_insert(value);
+        }
+    }
+    body
+}
+
+// Chat accepts a system prompt string and/or an options table ahead of its callbacks,
+// returns them together with the index of the first callback
+unsafe fn read_chat_arguments(lua: gmod::lua::State, index: i32) -> (CallOptions, i32) {
+    let system = if lua.lua_type(index) == gmod::lua::LUA_TSTRING {
+        Some(lua.check_string(index).to_string())
+    } else {
+        None
+    };
+    let index = if system.is_some() { index + 1 } else { index };
+
+    let (mut options, callback_index) = read_generation_arguments(lua, index);
+
+    if system.is_some() {
+        options.system = system;
+    }
+    (options, callback_index)
+}
+
+// Named option tables from RegisterPreset, already merged with the presets they were based on
+static PRESETS: Mutex<BTreeMap<String, HashMap<String, serde_json::Value>>> = Mutex::new(BTreeMap::new());
+
+unsafe fn get_preset(lua: gmod::lua::State, name: &str) -> HashMap<String, serde_json::Value> {
+    // Cloned before erroring, lua.error never returns to unlock the mutex
+    let preset = PRESETS.lock().unwrap().get(name).cloned();
+    match preset {
+        Some(preset) => preset,
+        None => lua.error(format!("Unknown preset '{}'", name)),
+    }
+}
+
+// Reads an options table, filling in what it leaves out from the preset its preset key names
+unsafe fn read_preset_table(lua: gmod::lua::State, index: i32) -> HashMap<String, serde_json::Value> {
+    let mut options: HashMap<String, serde_json::Value> = match lua_to_json(lua, index) {
+        serde_json::Value::Object(options) => options.into_iter().collect(),
+        _ => HashMap::new(),
+    };
+
+    match options.remove("preset") {
+        Some(serde_json::Value::String(name)) => {
+            for (key, value) in get_preset(lua, &name) {
+                options.entry(key).or_insert(value);
+            }
+        },
+        Some(_) => lua.error("preset must be a string"),
+        None => {},
+    }
+    options
+}
+
+// Optional max_tokens number and/or options table starting at index, returns them with the
+// index of the callback that follows. max_tokens is num_predict, setting both is an error
+unsafe fn read_generation_arguments(lua: gmod::lua::State, index: i32) -> (CallOptions, i32) {
+    let max_tokens = if lua.lua_type(index) == gmod::lua::LUA_TNUMBER {
+        let max_tokens = lua.to_number(index);
+        if max_tokens < 1.0 || max_tokens.fract() != 0.0 {
+            lua.error("max_tokens must be a positive integer");
+        }
+        Some(max_tokens as u64)
+    } else {
+        None
+    };
+    let index = if max_tokens.is_some() { index + 1 } else { index };
+
+    let (mut options, callback_index) = if lua.is_function(index) {
+        (CallOptions::default(), index)
+    } else {
+        (read_options(lua, index), index + 1)
+    };
+
+    if let Some(max_tokens) = max_tokens {
+        let model_options = options.options.get_or_insert_with(HashMap::new);
+        if model_options.contains_key("num_predict") {
+            lua.error("max_tokens and the num_predict option can't both be set");
+        }
+        model_options.insert("num_predict".to_string(), serde_json::Value::from(max_tokens));
+    }
+    (options, callback_index)
+}
+
+// Lays the per-call options over the configured defaults, key by key
+fn with_default_options(options: Option<HashMap<String, serde_json::Value>>) -> Option<HashMap<String, serde_json::Value>> {
+    let mut merged = get_config().options.clone();
+    merged.extend(options.unwrap_or_default());
+    if merged.is_empty() { None } else { Some(merged) }
+}
+
+// Leads the chat with the call's system prompt, or the configured default_system when
+// the history doesn't bring its own
+fn apply_system_prompt(messages: &mut Vec<ChatMessage>, system: Option<&str>) {
+    let default_system = get_config().default_system.clone();
+    let system = match (system, default_system) {
+        (Some(system), _) => system.to_string(),
+        (None, Some(default_system)) if !messages.iter().any(|message| message.role == "system") => default_system,
+        _ => return,
+    };
+
+    messages.insert(0, ChatMessage {
+        role: "system".to_string(),
+        content: system,
+        ..Default::default()
+    });
+}
+
+// Model argument of Generate/Chat, nil falls back to the configured default_model
+unsafe fn read_model_name(lua: gmod::lua::State, index: i32) -> String {
+    if !lua.is_none(index) && !lua.is_nil(index) {
+        return lua.check_string(index).to_string();
+    }
+
+    match &get_config().default_model {
+        Some(model) => model.clone(),
+        None => lua.error("Model name is required when no default_model is configured"),
+    }
+}
+
+// OpenAI-compatible backends take sampling options at the top level of the request
+// and structured output as response_format
+fn openai_options(options: CallOptions) -> HashMap<String, serde_json::Value> {
+    let mut openai_options: HashMap<String, serde_json::Value> = with_default_options(options.options).unwrap_or_default()
+        .into_iter()
+        .map(|(key, value)| match key.as_str() {
+            "num_predict" => ("max_tokens".to_string(), value),
+            _ => (key, value),
+        })
+        .collect();
+
+    let response_format = match options.format {
+        Some(serde_json::Value::Object(schema)) => Some(serde_json::json!({
+            "type": "json_schema",
+            "json_schema": { "name": "response", "schema": schema },
+        })),
+        Some(_) => Some(serde_json::json!({ "type": "json_object" })),
+        None => None,
+    };
+    if let Some(response_format) = response_format {
+        openai_options.insert("response_format".to_string(), response_format);
+    }
+
+    if let Some(tools) = options.tools {
+        openai_options.insert("tools".to_string(), serde_json::Value::Array(tools));
+    }
+
+    openai_options
+}
+
+// References the callback at callback_index, or with in_coroutine the coroutine calling us
+unsafe fn read_callback(lua: gmod::lua::State, callback_index: i32, in_coroutine: bool) -> i32 {
+    if in_coroutine {
+        lua.get_global(lua_string!("coroutine"));
+        lua.get_field(-1, lua_string!("running"));
+        lua.call(0, 1);
+        if lua.is_nil(-1) {
+            lua.error("Must be called from inside a coroutine");
+        }
+        lua.remove(-2); // Pop the coroutine library
+        return lua.reference();
+    }
+
+    // Callback function is required
+    if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+        lua.error("Callback function is required");
+    }
+
+    lua.push_value(callback_index);
+    lua.reference()
+}
+
+#[lua_function]
+fn ollama_generate_async(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        generate(lua, true);
+        lua.pop(); // The request id, the coroutine gets it with the result
+        lua.coroutine_yield(0)
+    }
+}
+
+#[lua_function]
+fn ollama_chat_async(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        chat(lua, true);
+        lua.pop();
+        lua.coroutine_yield(0)
+    }
+}
+
+// Shared by Generate and GenerateAsync, the latter resumes the running coroutine with the
+// result instead of calling a callback
+unsafe fn generate(lua: gmod::lua::State, in_coroutine: bool) -> i32 {
+    let model = check_model_name(lua, &read_model_name(lua, 1));
+    let prompt = lua.check_string(2).to_string();
+
+    // Optional system prompt
+    let system = if lua.get_top() >= 3 && !lua.is_nil(3) {
+        Some(lua.check_string(3).to_string())
+    } else {
+        None
+    };
+
+    // Optional max_tokens and/or options table, the callback then moves over
+    let (options, callback_index) = read_generation_arguments(lua, 4);
+    let callback_ref = read_callback(lua, callback_index, in_coroutine);
+    let request_id = register_request(lua, vec![callback_ref]);
+    tag_request(request_id, options.id.clone());
+
+    // Refused right away without reaching the server
+    if let Err(error) = check_rate_limit(options.rate_limit_key.as_deref()) {
+        push_callback(callback_ref, request_id, CallbackData::Error { error });
+        lua.push_integer(request_id as isize);
+        return 1;
+    }
+
+    let raw_response = options.raw_response == Some(true);
+    let request = with_extra_fields(GenerateRequest {
+        model: model.clone(),
+        prompt: prompt.clone(),
+        // A system prompt can't be applied without the template
+        system: if options.raw == Some(true) { None } else { system.or(options.system).or_else(|| get_config().default_system.clone()) },
+        suffix: options.suffix,
+        stream: Some(false),
+        template: options.template,
+        raw: options.raw,
+        context: options.context,
+        images: options.images,
+        options: with_default_options(options.options),
+        keep_alive: options.keep_alive.or_else(|| get_config().keep_alive.clone()),
+        format: options.format,
+        think: options.think,
+    }, options.extra);
+
+    let client = get_client();
+    let config = get_config();
+    let base_url = options.base_url.unwrap_or_else(|| config.base_url.clone());
+    let url = format!("{}/api/generate", base_url);
+
+    if config.dry_run {
+        queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+        lua.push_integer(request_id as isize);
+        return 1;
+    }
+
+    // Async execution with callback
+    let key = dedupe_key(&url, &request, if raw_response { "raw" } else { "generate" });
+    spawn_shared_request(key, callback_ref, request_id, async move {
+        if raw_response {
+            return send_raw(client.post(&url).json(&request)).await;
+        }
+
+        let result = send_json::<GenerateResponse>(client.post(&url).json(&request)).await;
+
+        // Map the response to the callback data
+        match result {
+            Ok(response) => CallbackData::Generate {
+                metrics: GenerationMetrics::from_generate(&response),
+                response: response.response,
+                model: response.model,
+                context: response.context.unwrap_or_default(),
+                thinking: response.thinking.filter(|thinking| !thinking.is_empty()),
+                done_reason: response.done_reason,
+            },
+            Err(error) => CallbackData::Error { error },
+        }
+    });
+
+    lua.push_integer(request_id as isize);
+    1
+}
+
+#[lua_function]
+fn ollama_generate(lua: gmod::lua::State) -> i32 {
+    unsafe { generate(lua, false) }
+}
+
+// GMod's working directory is the game root, the data folder lives under garrysmod/.
+// Like resolve_image_path the folders are canonicalized, so symlinks inside the data folder
+// can't send the write anywhere else
+fn create_data_file(path: &str) -> Result<std::io::BufWriter<std::fs::File>, RequestError> {
+    let outside = || RequestError::new("file", "output_file leads outside of the data folder");
+    let root = std::path::Path::new("garrysmod/data").canonicalize().map_err(RequestError::file)?;
+    let path = root.join(path);
+    let parent = path.parent().ok_or_else(outside)?;
+
+    // Checked before creating missing folders too, so none get created outside of it
+    let existing = parent.ancestors().find(|ancestor| ancestor.exists()).ok_or_else(outside)?;
+    if !existing.canonicalize().map_err(RequestError::file)?.starts_with(&root) {
+        return Err(outside());
+    }
+
+    std::fs::create_dir_all(parent).map_err(RequestError::file)?;
+    if !parent.canonicalize().map_err(RequestError::file)?.starts_with(&root)
+        || std::fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink())
+    {
+        return Err(outside());
+    }
+
+    let file = std::fs::File::create(path).map_err(RequestError::file)?;
+    Ok(std::io::BufWriter::new(file))
+}
+
+// Numbers the chunks of a stream and times the gap before each one
+struct ChunkTimer {
+    index: u32,
+    last: Instant,
+}
+
+impl ChunkTimer {
+    fn start() -> Self {
+        Self { index: 0, last: Instant::now() }
+    }
+
+    fn next(&mut self) -> (u32, f64) {
+        let now = Instant::now();
+        let delta_ms = now.duration_since(self.last).as_secs_f64() * 1000.0;
+        self.index += 1;
+        self.last = now;
+        (self.index, delta_ms)
+    }
+}
+
+// Reads a streamed response body line by line until on_line produces the final callback data
+async fn read_stream_lines<F>(mut response: reqwest::Response, mut on_line: F) -> Result<CallbackData, RequestError>
+where
+    F: FnMut(String) -> Result<Option<CallbackData>, RequestError>,
+{
+    let mut lines = LineBuffer::default();
+    let mut ended = false;
+
+    while !ended {
+        match response.chunk().await? {
+            Some(chunk) => lines.push(&chunk),
+            None => ended = true,
+        }
+
+        while let Some(line) = lines.next_line().or_else(|| if ended { lines.finish() } else { None }) {
+            if line.contains("\"error\"") {
+                if let Some(error) = embedded_error(&serde_json::from_str(&line).unwrap_or_default()) {
+                    return Err(RequestError::new("stream", error));
+                }
+            }
+
+            // Streamed chunks are queued as they're read, hold off while the queue is full
+            wait_for_callback_space().await;
+            if let Some(data) = on_line(line)? {
+                return Ok(data);
+            }
+        }
+    }
+
+    Err(RequestError::new("stream", "stream ended before completion"))
+}
+
+#[lua_function]
+fn ollama_generate_stream(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model = check_model_name(lua, &read_model_name(lua, 1));
+        let prompt = lua.check_string(2).to_string();
+
+        // Optional max_tokens and/or options table, the callbacks then move over
+        let (options, token_index) = read_generation_arguments(lua, 3);
+
+        // Both callbacks are required, except the token one when writing to a file
+        let token_callback = lua.is_function(token_index) || (options.output_file.is_some() && lua.is_nil(token_index));
+        if lua.get_top() < token_index + 1 || !token_callback || !lua.is_function(token_index + 1) {
+            lua.error("Token and done callback functions are required");
+        }
+
+        lua.push_value(token_index);
+        let token_ref = lua.reference();
+        lua.push_value(token_index + 1);
+        let done_ref = lua.reference();
+        let request_id = register_request(lua, vec![token_ref, done_ref]);
+        let output_file = options.output_file.clone();
+        tag_request(request_id, options.id.clone());
+
+        // Refused right away without reaching the server
+        if let Err(error) = check_rate_limit(options.rate_limit_key.as_deref()) {
+            push_callback(done_ref, request_id, CallbackData::Error { error });
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        let request = with_extra_fields(GenerateRequest {
+            model: model.clone(),
+            prompt,
+            system: if options.raw == Some(true) { None } else { options.system.or_else(|| get_config().default_system.clone()) },
+            suffix: options.suffix,
+            stream: Some(true),
+            template: options.template,
+            raw: options.raw,
+            context: options.context,
+            images: options.images,
+            options: with_default_options(options.options),
+            keep_alive: options.keep_alive.or_else(|| get_config().keep_alive.clone()),
+            format: options.format,
+            think: options.think,
+        }, options.extra);
+
+        let client = get_client();
+        let config = get_config();
+        let base_url = options.base_url.unwrap_or_else(|| config.base_url.clone());
+        let url = format!("{}/api/generate", base_url);
+
+        if config.dry_run {
+            queue_dry_run(done_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution, every NDJSON line is queued as its own token callback
+        // or, with output_file, appended to the file instead
+        spawn_task(request_id, async move {
+            let result = async {
+                let mut timer = ChunkTimer::start();
+                let response = send_request(client.post(&url).json(&request)).await?;
+
+                // Dropped (and so closed) on errors too, keeping what was written so far
+                let mut file = match &output_file {
+                    Some(output_file) => Some(create_data_file(output_file)?),
+                    None => None,
+                };
+
+                let mut full_response = String::new();
+                let mut full_thinking = String::new();
+                read_stream_lines(response, |line| {
+                    let part = serde_json::from_str::<GenerateResponse>(&line)?;
+                    full_thinking.push_str(part.thinking.as_deref().unwrap_or_default());
+
+                    match &mut file {
+                        Some(file) => file.write_all(part.response.as_bytes()).map_err(RequestError::file)?,
+                        None => full_response.push_str(&part.response),
+                    }
+
+                    if part.done {
+                        if let Some(file) = &mut file {
+                            file.flush().map_err(RequestError::file)?;
+                        }
+
+                        return Ok(Some(CallbackData::GenerateDone {
+                            metrics: GenerationMetrics::from_generate(&part),
+                            response: std::mem::take(&mut full_response),
+                            model: part.model,
+                            context: part.context.unwrap_or_default(),
+                            thinking: Some(std::mem::take(&mut full_thinking)).filter(|thinking| !thinking.is_empty()),
+                            done_reason: part.done_reason,
+                            output_file: output_file.clone(),
+                        }));
+                    }
+
+                    if file.is_none() {
+                        let (index, delta_ms) = timer.next();
+                        push_callback(token_ref, request_id, CallbackData::GenerateToken {
+                            token: part.response,
+                            index,
+                            delta_ms,
+                        });
+                    }
+                    Ok(None)
+                }).await
+            }.await;
+
+            let data = result.unwrap_or_else(|error| CallbackData::Error { error });
+            wait_for_callback_space().await;
+            push_callback(done_ref, request_id, data);
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_count_tokens(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model = check_model_name(lua, &read_model_name(lua, 1));
+        let text = lua.check_string(2).to_string();
+
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 3);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        // Ollama has no tokenizer endpoint, but evaluating the prompt without generating
+        // anything reports its length. raw leaves the model's template out of the count
+        let mut options = with_default_options(None).unwrap_or_default();
+        options.insert("num_predict".to_string(), serde_json::Value::from(0));
+
+        let request = GenerateRequest {
+            model: model.clone(),
+            prompt: text,
+            system: None,
+            suffix: None,
+            stream: Some(false),
+            template: None,
+            raw: Some(true),
+            context: None,
+            images: None,
+            options: Some(options),
+            keep_alive: get_config().keep_alive.clone(),
+            format: None,
+            think: None,
+        };
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/generate", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_json::<GenerateResponse>(client.post(&url).json(&request)).await;
+
+            match result {
+                Ok(GenerateResponse { prompt_eval_count: Some(tokens), .. }) => CallbackData::CountTokens {
+                    model,
+                    tokens,
+                },
+                Ok(_) => CallbackData::Error {
+                    error: RequestError::new("decode", "response contained no prompt_eval_count"),
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+// Shared by Chat and ChatAsync, see generate
+unsafe fn chat(lua: gmod::lua::State, in_coroutine: bool) -> i32 {
+    // OpenAI-compatible backends don't follow Ollama's name:tag convention
+    let model = if get_config().openai_compat {
+        check_verbatim_model_name(lua, &read_model_name(lua, 1))
+    } else {
+        check_model_name(lua, &read_model_name(lua, 1))
+    };
+
+    let mut messages = read_chat_messages(lua, 2);
+
+    // Optional system prompt and/or options table before the callback
+    let (options, callback_index) = read_chat_arguments(lua, 3);
+    apply_system_prompt(&mut messages, options.system.as_deref());
+    let callback_ref = read_callback(lua, callback_index, in_coroutine);
+    let request_id = register_request(lua, vec![callback_ref]);
+    tag_request(request_id, options.id.clone());
+    track_history(lua, request_id, 2);
+
+    // Refused right away without reaching the server
+    if let Err(error) = check_rate_limit(options.rate_limit_key.as_deref()) {
+        push_callback(callback_ref, request_id, CallbackData::Error { error });
+        lua.push_integer(request_id as isize);
+        return 1;
+    }
+
+    let client = get_client();
+    let config = get_config();
+    let base_url = options.base_url.clone().unwrap_or_else(|| config.base_url.clone());
+    let raw_response = options.raw_response == Some(true);
+
+    // OpenAI-compatible mode uses its own route and request/response shape
+    if config.openai_compat {
+        // openai_options takes the whole options
+        let extra = options.extra.clone();
+        let request = with_extra_fields(OpenAIChatRequest {
+            model: model.clone(),
+            messages,
+            stream: Some(false),
+            options: openai_options(options),
+        }, extra);
+
+        let url = format!("{}/v1/chat/completions", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        let key = dedupe_key(&url, &request, if raw_response { "raw" } else { "chat" });
+        spawn_shared_request(key, callback_ref, request_id, async move {
+            if raw_response {
+                return send_raw(client.post(&url).json(&request)).await;
+            }
+
+            let result = send_json::<OpenAIChatResponse>(client.post(&url).json(&request)).await;
+
+            // Map the first choice to the same data as a native chat response
+            match result {
+                Ok(response) => match response.choices.into_iter().next() {
+                    Some(choice) => CallbackData::Chat {
+                        content: choice.message.content.unwrap_or_default(),
+                        role: choice.message.role,
+                        model: response.model,
+                        tool_calls: choice.message.tool_calls.unwrap_or_default()
+                            .into_iter()
+                            .map(|tool_call| ToolCall {
+                                function: ToolCallFunction {
+                                    arguments: serde_json::from_str(&tool_call.function.arguments)
+                                        .unwrap_or(serde_json::Value::String(tool_call.function.arguments)),
+                                    name: tool_call.function.name,
+                                },
+                            })
+                            .collect(),
+                        // OpenAI's finish_reason uses the same "stop"/"length" values
+                        thinking: None,
+                        done_reason: choice.finish_reason,
+                        metrics: GenerationMetrics::default(),
+                    },
+                    None => CallbackData::Error {
+                        error: RequestError::new("decode", "response contained no choices"),
+                    },
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        return 1;
+    }
+
+    let request = with_extra_fields(ChatRequest {
+        model: model.clone(),
+        messages,
+        stream: Some(false),
+        options: with_default_options(options.options),
+        keep_alive: options.keep_alive.or_else(|| get_config().keep_alive.clone()),
+        format: options.format,
+        tools: options.tools,
+        think: options.think,
+    }, options.extra);
+
+    let url = format!("{}/api/chat", base_url);
+
+    if config.dry_run {
+        queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+        lua.push_integer(request_id as isize);
+        return 1;
+    }
+
+    // Async execution with callback
+    let key = dedupe_key(&url, &request, if raw_response { "raw" } else { "chat" });
+    spawn_shared_request(key, callback_ref, request_id, async move {
+        if raw_response {
+            return send_raw(client.post(&url).json(&request)).await;
+        }
+
+        let result = send_json::<ChatResponse>(client.post(&url).json(&request)).await;
+
+        // Map the response to the callback data
+        match result {
+            Ok(response) => CallbackData::Chat {
+                metrics: GenerationMetrics::from_chat(&response),
+                content: response.message.content,
+                role: response.message.role,
+                model: response.model,
+                tool_calls: response.message.tool_calls.unwrap_or_default(),
+                thinking: response.message.thinking.filter(|thinking| !thinking.is_empty()),
+                done_reason: response.done_reason,
+            },
+            Err(error) => CallbackData::Error { error },
+        }
+    });
+
+    lua.push_integer(request_id as isize);
+    1
+}
+
+#[lua_function]
+fn ollama_chat(lua: gmod::lua::State) -> i32 {
+    unsafe { chat(lua, false) }
+}
+
+#[lua_function]
+fn ollama_chat_stream(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        // OpenAI-compatible backends don't follow Ollama's name:tag convention
+        let model = if get_config().openai_compat {
+            check_verbatim_model_name(lua, &read_model_name(lua, 1))
+        } else {
+            check_model_name(lua, &read_model_name(lua, 1))
+        };
+
+        let mut messages = read_chat_messages(lua, 2);
+
+        // Optional system prompt and/or options table before the callbacks
+        let (options, delta_index) = read_chat_arguments(lua, 3);
+        apply_system_prompt(&mut messages, options.system.as_deref());
+
+        // Both callbacks are required
+        if lua.get_top() < delta_index + 1 || !lua.is_function(delta_index) || !lua.is_function(delta_index + 1) {
+            lua.error("Delta and done callback functions are required");
+        }
+
+        lua.push_value(delta_index);
+        let delta_ref = lua.reference();
+        lua.push_value(delta_index + 1);
+        let done_ref = lua.reference();
+        let request_id = register_request(lua, vec![delta_ref, done_ref]);
+        tag_request(request_id, options.id.clone());
+        track_history(lua, request_id, 2);
+
+        // Refused right away without reaching the server
+        if let Err(error) = check_rate_limit(options.rate_limit_key.as_deref()) {
+            push_callback(done_ref, request_id, CallbackData::Error { error });
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        let client = get_client();
+        let config = get_config();
+        let base_url = options.base_url.clone().unwrap_or_else(|| config.base_url.clone());
+
+        // OpenAI-compatible backends stream server-sent events instead of NDJSON
+        if config.openai_compat {
+            let extra = options.extra.clone();
+            let request = with_extra_fields(OpenAIChatRequest {
+                model: model.clone(),
+                messages,
+                stream: Some(true),
+                options: openai_options(options),
+            }, extra);
+
+            let url = format!("{}/v1/chat/completions", base_url);
+
+            if config.dry_run {
+                queue_dry_run(done_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+                lua.push_integer(request_id as isize);
+                return 1;
+            }
+
+            spawn_task(request_id, async move {
+                let result = async {
+                    let mut timer = ChunkTimer::start();
+                    let response = send_request(client.post(&url).json(&request)).await?;
+
+                    let mut full_content = String::new();
+                    let mut role = "assistant".to_string();
+                    let mut model = model;
+                    let mut done_reason = None;
+                    read_stream_lines(response, |line| {
+                        // Only data fields matter, comments and other SSE fields are skipped
+                        let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                            return Ok(None);
+                        };
+
+                        if data == "[DONE]" {
+                            return Ok(Some(CallbackData::ChatDone {
+                                content: std::mem::take(&mut full_content),
+                                role: role.clone(),
+                                model: model.clone(),
+                                tool_calls: Vec::new(),
+                                thinking: None,
+                                done_reason: done_reason.take(),
+                                metrics: GenerationMetrics::default(),
+                            }));
+                        }
+
+                        let chunk = serde_json::from_str::<OpenAIChatStreamChunk>(data)?;
+                        model = chunk.model;
+
+                        if let Some(choice) = chunk.choices.into_iter().next() {
+                            // Only the last chunk carries the finish_reason
+                            if choice.finish_reason.is_some() {
+                                done_reason = choice.finish_reason;
+                            }
+
+                            let delta = choice.delta;
+                            if let Some(delta_role) = delta.role {
+                                role = delta_role;
+                            }
+
+                            if let Some(content) = delta.content.filter(|content| !content.is_empty()) {
+                                full_content.push_str(&content);
+                                let (index, delta_ms) = timer.next();
+                                push_callback(delta_ref, request_id, CallbackData::ChatDelta { content, index, delta_ms });
+                            }
+                        }
+
+                        Ok(None)
+                    }).await
+                }.await;
+
+                let data = result.unwrap_or_else(|error| CallbackData::Error { error });
+                wait_for_callback_space().await;
+                push_callback(done_ref, request_id, data);
+            });
+
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        let request = with_extra_fields(ChatRequest {
+            model: model.clone(),
+            messages,
+            stream: Some(true),
+            options: with_default_options(options.options),
+            keep_alive: options.keep_alive.or_else(|| get_config().keep_alive.clone()),
+            format: options.format,
+            tools: options.tools,
+            think: options.think,
+        }, options.extra);
+
+        let url = format!("{}/api/chat", base_url);
+
+        if config.dry_run {
+            queue_dry_run(done_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution, every NDJSON line is queued as its own delta callback
+        spawn_task(request_id, async move {
+            let result = async {
+                let mut timer = ChunkTimer::start();
+                let response = send_request(client.post(&url).json(&request)).await?;
+
+                let mut full_content = String::new();
+                let mut full_thinking = String::new();
+                let mut tool_calls = Vec::new();
+                read_stream_lines(response, |line| {
+                    let mut part = serde_json::from_str::<ChatResponse>(&line)?;
+                    full_content.push_str(&part.message.content);
+                    full_thinking.push_str(part.message.thinking.as_deref().unwrap_or_default());
+
+                    // Tool calls come in whichever chunk the model produced them
+                    tool_calls.extend(part.message.tool_calls.take().unwrap_or_default());
+
+                    if part.done {
+                        return Ok(Some(CallbackData::ChatDone {
+                            metrics: GenerationMetrics::from_chat(&part),
+                            content: std::mem::take(&mut full_content),
+                            role: part.message.role,
+                            model: part.model,
+                            tool_calls: std::mem::take(&mut tool_calls),
+                            thinking: Some(std::mem::take(&mut full_thinking)).filter(|thinking| !thinking.is_empty()),
+                            done_reason: part.done_reason,
+                        }));
+                    }
+
+                    let (index, delta_ms) = timer.next();
+                    push_callback(delta_ref, request_id, CallbackData::ChatDelta {
+                        content: part.message.content,
+                        index,
+                        delta_ms,
+                    });
+                    Ok(None)
+                }).await
+            }.await;
+
+            let data = result.unwrap_or_else(|error| CallbackData::Error { error });
+            wait_for_callback_space().await;
+            push_callback(done_ref, request_id, data);
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_list_models(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        // Optional name filter, sort key and {base_url = ...} table before the callback, the
+        // first two can be nil
+        let callback_index = lua.get_top().max(1);
+        let options_index = if callback_index > 1 && lua.is_table(callback_index - 1) { callback_index - 1 } else { callback_index };
+        if !lua.is_function(callback_index) || options_index > 3 {
+            lua.error("Callback function is required");
+        }
+        let (base_url, _) = read_server_options(lua, options_index);
+
+        let filter = if options_index > 1 && !lua.is_nil(1) {
+            lua.check_string(1).to_lowercase()
+        } else {
+            String::new()
+        };
+
+        let sort = if options_index > 2 && !lua.is_nil(2) {
+            let sort = lua.check_string(2).to_string();
+            if !matches!(sort.as_str(), "name" | "size" | "modified") {
+                lua.error("Sort must be \"name\", \"size\" or \"modified\"");
+            }
+            Some(sort)
+        } else {
+            None
+        };
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/tags", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "GET", url, String::new());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_json::<ModelsResponse>(client.get(&url)).await;
+
+                    // Map the response to the callback data
+            match result {
+                Ok(response) => {
+                    let mut models: Vec<ModelInfo> = response.models.into_iter()
+                        .filter(|model| model.name.to_lowercase().contains(&filter))
+                        .collect();
+
+                    // Sizes and dates put the largest and newest first
+                    match sort.as_deref() {
+                        Some("name") => models.sort_by(|a, b| a.name.cmp(&b.name)),
+                        Some("size") => models.sort_by_key(|model| std::cmp::Reverse(model.size)),
+                        Some("modified") => models.sort_by_key(|model| std::cmp::Reverse(parse_rfc3339(&model.modified_at))),
+                        _ => {}
+                    }
+
+                    CallbackData::ListModels { models }
+                }
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_get_model_info(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model_name = check_model_name(lua, &lua.check_string(1));
+
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 2);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let request = ShowRequest {
+            name: model_name.clone(),
+        };
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/show", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_json::<ShowResponse>(client.post(&url).json(&request)).await;
+
+            // Map the response to the callback data
+            match result {
+                Ok(response) => CallbackData::GetModelInfo {
+                    license: response.license.unwrap_or_else(|| "".to_string()),
+                    modelfile: response.modelfile.unwrap_or_else(|| "".to_string()),
+                    parameters: response.parameters.unwrap_or_else(|| "".to_string()),
+                    template: response.template.unwrap_or_else(|| "".to_string()),
+                    details: response.details,
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_is_model_available(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model_name = check_model_name(lua, &lua.check_string(1));
+
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 2);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/tags", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "GET", url, String::new());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_json::<ModelsResponse>(client.get(&url)).await;
+
+            // Map the response to the callback data
+            match result {
+                Ok(response) => {
+                    let is_available = response.models.iter().any(|model| model.name == model_name);
+                    CallbackData::IsModelAvailable { is_available }
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_generate_embeddings(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model = check_model_name(lua, &lua.check_string(1));
+
+        // Second argument can be a string or table of strings
+        let input = if lua.is_table(2) {
+            // Handle array of strings
+            let mut inputs = Vec::new();
+            let mut i = 1;
+            loop {
+                lua.push_integer(i as isize);
+                lua.get_table(2);
+
+                if lua.is_nil(-1) {
+                    lua.pop();
+                    break;
+                }
+
+                if let Some(text) = lua.get_string(-1) {
+                    inputs.push(text.to_string());
+                }
+
+                lua.pop();
+                i += 1;
+            }
+            serde_json::Value::Array(inputs.into_iter().map(serde_json::Value::String).collect())
+        } else {
+            // Handle single string
+            let text = lua.check_string(2).to_string();
+            serde_json::Value::String(text)
+        };
+
+        // Optional truncate flag or settings table before the callback, with truncate false
+        // Ollama errors on inputs longer than the context window instead of cutting them
+        let input_was_batch = input.is_array();
+        let mut base_url = None;
+        let mut include_vectors = true;
+        let mut store_handles = false;
+        let (truncate, callback_index) = if lua.is_function(3) {
+            (true, 3)
+        } else if lua.is_nil(3) {
+            (true, 4)
+        } else if lua.lua_type(3) == gmod::lua::LUA_TBOOLEAN {
+            (lua.get_boolean(3), 4)
+        } else if lua.is_table(3) {
+            base_url = get_string_field(lua, 3, lua_string!("base_url"));
+            include_vectors = get_bool_field(lua, 3, lua_string!("vectors")).unwrap_or(true);
+            store_handles = get_bool_field(lua, 3, lua_string!("handles")).unwrap_or(false);
+            (get_bool_field(lua, 3, lua_string!("truncate")).unwrap_or(true), 4)
+        } else {
+            lua.error("truncate must be a boolean or a settings table");
+        };
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let request = EmbedRequest {
+            model: model.clone(),
+            input,
+            truncate: Some(truncate),
+            options: None,
+        };
+
+        let client = get_client();
+        let config = get_config();
+        let base_url = base_url.unwrap_or_else(|| config.base_url.clone());
+
+        if config.dry_run {
+            let url = format!("{}/api/embed", base_url);
+            queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        let url = format!("{}/api/embed", base_url);
+        let variant = match (store_handles, include_vectors) {
+            (true, _) => "embed_handles",
+            (false, true) => "embed",
+            (false, false) => "embed_summary",
+        };
+        let key = dedupe_key(&url, &request, variant);
+        spawn_shared_request(key, callback_ref, request_id, async move {
+            let result = send_embed(&client, &base_url, &request).await;
+
+            // Map the response to the callback data
+            match result {
+                Ok(response) => CallbackData::Embeddings {
+                    model: response.model,
+                    embeddings: response.embeddings,
+                    include_vectors,
+                    store_handles,
+                    input_was_batch,
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_embed_document(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model = check_model_name(lua, &lua.check_string(1));
+        let text = lua.check_string(2).to_string();
+
+        // Optional chunking settings table
+        let (mut chunk_size, mut overlap) = (512, 64);
+        let mut base_url = None;
+        let callback_index = if lua.is_function(3) { 3 } else { 4 };
+        if callback_index == 4 && lua.is_table(3) {
+            base_url = get_string_field(lua, 3, lua_string!("base_url"));
+            if let Some(size) = get_number_field(lua, 3, lua_string!("chunk_size")) {
+                chunk_size = size as usize;
+            }
+            if let Some(size) = get_number_field(lua, 3, lua_string!("overlap")) {
+                overlap = size as usize;
+            }
+        }
+
+        if chunk_size == 0 || overlap >= chunk_size {
+            lua.error("chunk_size must be positive and greater than overlap");
+        }
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let chunks = chunk_text(&text, chunk_size, overlap);
+        let requests: Vec<EmbedRequest> = chunks.chunks(EMBED_BATCH_SIZE)
+            .map(|batch| EmbedRequest {
+                model: model.clone(),
+                input: serde_json::Value::Array(batch.iter().cloned().map(serde_json::Value::String).collect()),
+                truncate: Some(true),
+                options: None,
+            })
+            .collect();
+
+        let client = get_client();
+        let config = get_config();
+        let base_url = base_url.unwrap_or_else(|| config.base_url.clone());
+
+        if config.dry_run {
+            let url = format!("{}/api/embed", base_url);
+            queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&requests).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback, embedding one batch at a time
+        spawn_request(callback_ref, request_id, async move {
+            let result = async {
+                let mut embeddings = Vec::with_capacity(chunks.len());
+                for request in &requests {
+                    let response = send_embed(&client, &base_url, request).await?;
+                    embeddings.extend(response.embeddings);
+                }
+                Ok::<_, RequestError>(embeddings)
+            }.await;
+
+            // Map the response to the callback data
+            match result {
+                Ok(embeddings) => CallbackData::EmbedDocument {
+                    chunks,
+                    embeddings,
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_get_running_models(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 1);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/ps", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "GET", url, String::new());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_json::<RunningModelsResponse>(client.get(&url)).await;
+
+            // Map the response to the callback data
+            match result {
+                Ok(response) => CallbackData::GetRunningModels {
+                    models: response.models,
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_pull_model(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model_name = check_model_name(lua, &lua.check_string(1));
+
+        // Optional {base_url = ...} table, the callbacks then move over
+        let (base_url, progress_index) = read_server_options(lua, 2);
+
+        // Both callbacks are required
+        if lua.get_top() < progress_index + 1 || !lua.is_function(progress_index) || !lua.is_function(progress_index + 1) {
+            lua.error("Progress and done callback functions are required");
+        }
+
+        lua.push_value(progress_index);
+        let progress_ref = lua.reference();
+        lua.push_value(progress_index + 1);
+        let done_ref = lua.reference();
+        let request_id = register_request(lua, vec![progress_ref, done_ref]);
+
+        let request = PullRequest {
+            name: model_name.clone(),
+            stream: Some(true),
+        };
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/pull", base_url);
+
+        if config.dry_run {
+            queue_dry_run(done_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution, every status line is queued as a progress callback
+        spawn_task(request_id, async move {
+            let result = async {
+                let response = send_request(client.post(&url).json(&request)).await?;
+
+                read_stream_lines(response, |line| {
+                    let part = serde_json::from_str::<PullResponse>(&line)?;
+
+                    // Failures are reported in-stream, possibly after some progress
+                    if let Some(error) = part.error {
+                        return Err(RequestError::new("stream", error));
+                    }
+
+                    let status = part.status.unwrap_or_default();
+                    if status == "success" {
+                        return Ok(Some(CallbackData::PullDone {
+                            model: model_name.clone(),
+                            status,
+                        }));
+                    }
+
+                    // total and completed are missing while the manifest is fetched
+                    push_callback(progress_ref, request_id, CallbackData::PullProgress {
+                        status,
+                        digest: part.digest,
+                        completed: part.completed,
+                        total: part.total,
+                    });
+                    Ok(None)
+                }).await
+            }.await;
+
+            let data = result.unwrap_or_else(|error| CallbackData::Error { error });
+            wait_for_callback_space().await;
+            push_callback(done_ref, request_id, data);
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_delete_model(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model_name = check_model_name(lua, &lua.check_string(1));
+
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 2);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let request = DeleteRequest {
+            name: model_name.clone(),
+        };
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/delete", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "DELETE", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_request(client.delete(&url).json(&request)).await;
+
+            // The body is empty on success, so only the status is checked
+            match result {
+                Ok(_) => CallbackData::DeleteModel {
+                    model: model_name,
+                },
+                Err(mut error) if error.http_status == Some(404) => {
+                    error.message = format!("model '{}' not found", model_name);
+                    CallbackData::Error { error }
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_unload_model(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model_name = check_model_name(lua, &lua.check_string(1));
+
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 2);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let request = UnloadRequest {
+            model: model_name.clone(),
+            prompt: String::new(),
+            stream: false,
+            keep_alive: 0,
+        };
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/generate", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_request(client.post(&url).json(&request)).await;
+
+            // Nothing gets generated, the response only confirms the unload
+            match result {
+                Ok(_) => CallbackData::UnloadModel {
+                    model: model_name,
+                },
+                Err(mut error) if error.http_status == Some(404) => {
+                    error.message = format!("model '{}' not found", model_name);
+                    CallbackData::Error { error }
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_preload(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let model_name = check_model_name(lua, &lua.check_string(1));
+
+        // Optional keep_alive and {base_url = ...} table, the callback then moves over
+        let (keep_alive, options_index) = if lua.is_function(2) || lua.is_table(2) {
+            (None, 2)
+        } else {
+            (Some(check_keep_alive(lua, lua_to_json(lua, 2))), 3)
+        };
+        let (base_url, callback_index) = read_server_options(lua, options_index);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        // Ollama only loads the model for an empty prompt
+        let request = GenerateRequest {
+            model: model_name.clone(),
+            prompt: String::new(),
+            system: None,
+            suffix: None,
+            stream: Some(false),
+            template: None,
+            raw: None,
+            context: None,
+            images: None,
+            options: None,
+            keep_alive: keep_alive.or_else(|| get_config().keep_alive.clone()),
+            format: None,
+            think: None,
+        };
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/generate", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_json::<GenerateResponse>(client.post(&url).json(&request)).await;
+
+            match result {
+                Ok(response) => CallbackData::Preload {
+                    model: model_name,
+                    load_duration: response.load_duration,
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_copy_model(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let source = check_model_name(lua, &lua.check_string(1));
+        let destination = check_model_name(lua, &lua.check_string(2));
+
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 3);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let request = CopyRequest {
+            source: source.clone(),
+            destination: destination.clone(),
+        };
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/copy", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "POST", url, serde_json::to_string(&request).unwrap_or_default());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_request(client.post(&url).json(&request)).await;
+
+            // Like delete, the body is empty on success so only the status is checked
+            match result {
+                Ok(_) => CallbackData::CopyModel {
+                    source,
+                    destination,
+                },
+                Err(mut error) if error.http_status == Some(404) => {
+                    error.message = format!("model '{}' not found", source);
+                    CallbackData::Error { error }
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_get_version(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 1);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/version", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "GET", url, String::new());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Async execution with callback
+        spawn_request(callback_ref, request_id, async move {
+            let result = send_json::<VersionResponse>(client.get(&url)).await;
+
+            // Map the response to the callback data
+            match result {
+                Ok(response) => CallbackData::Version {
+                    version: response.version,
+                },
+                Err(error) => CallbackData::Error { error },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_ping(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 1);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/tags", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "GET", url, String::new());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Always a fresh request, and a single attempt so retries don't skew the latency. The
+        // short health check timeout keeps a host dropping packets from holding it up
+        spawn_request(callback_ref, request_id, async move {
+            let start = Instant::now();
+            let result = send_request_once(client.get(&url).timeout(HEALTH_CHECK_TIMEOUT)).await;
+            let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+            // Auth failures and server errors stay http errors, anything else but Ollama's
+            // models list means something else is listening
+            match result {
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    match classify_probe(status, &response.text().await.unwrap_or_default()) {
+                        ServerState::Ok => CallbackData::Ping { latency_ms },
+                        _ => CallbackData::Error { error: RequestError::not_ollama(status) },
+                    }
+                },
+                Err(error) => match error.http_status.map(|status| classify_probe(status, error.body.as_deref().unwrap_or_default())) {
+                    Some(ServerState::NotOllama) => CallbackData::Error {
+                        error: RequestError { body: error.body, ..RequestError::not_ollama(error.http_status.unwrap_or_default()) },
                     },
+                    _ => CallbackData::Error { error },
                 },
-            };
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_diagnose(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        // Optional {base_url = ...} table, the callback then moves over
+        let (base_url, callback_index) = read_server_options(lua, 1);
+
+        // Callback function is required
+        if lua.get_top() < callback_index || !lua.is_function(callback_index) {
+            lua.error("Callback function is required");
+        }
+
+        lua.push_value(callback_index);
+        let callback_ref = lua.reference();
+        let request_id = register_request(lua, vec![callback_ref]);
+
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/tags", base_url);
+
+        if config.dry_run {
+            queue_dry_run(callback_ref, request_id, "GET", url, String::new());
+            lua.push_integer(request_id as isize);
+            return 1;
+        }
+
+        // Everything the server answered with, error statuses included, so failures
+        // aren't reported as the callback's err but as part of the diagnostics
+        spawn_request(callback_ref, request_id, async move {
+            let start = Instant::now();
+            let result = client.get(&url).timeout(HEALTH_CHECK_TIMEOUT).send().await;
+            let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+            match result {
+                Ok(response) => {
+                    let status = response.status().as_u16();
+                    let headers = response.headers().iter()
+                        .filter_map(|(name, value)| Some((name.as_str().to_string(), value.to_str().ok()?.to_string())))
+                        .collect();
+
+                    let state = classify_probe(status, &response.text().await.unwrap_or_default());
+                    CallbackData::Diagnose {
+                        state,
+                        http_status: Some(status),
+                        latency_ms,
+                        headers,
+                        error: match state {
+                            ServerState::Ok => None,
+                            ServerState::Unauthorized => Some(RequestError {
+                                http_status: Some(status),
+                                ..RequestError::new("http", format!("the server responded with HTTP {}, check the api_key", status))
+                            }),
+                            ServerState::ServerError => Some(RequestError {
+                                http_status: Some(status),
+                                ..RequestError::new("http", format!("the server responded with HTTP {}", status))
+                            }),
+                            _ => Some(RequestError::not_ollama(status)),
+                        },
+                    }
+                },
+                Err(error) => CallbackData::Diagnose {
+                    state: ServerState::Unreachable,
+                    http_status: None,
+                    latency_ms,
+                    headers: Vec::new(),
+                    error: Some(RequestError::from(error)),
+                },
+            }
+        });
+
+        lua.push_integer(request_id as isize);
+        1
+    }
+}
+
+// Cached server status with its age, refreshing it in the background once stale.
+// Returns what answered, how old that is and whether a refresh was just started
+fn check_running() -> (ServerState, Duration, bool) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return (ServerState::Disabled, Duration::ZERO, false);
+    }
+
+    let cache = get_running_cache();
+
+    // Claims the refresh under the lock, so a stale cache starts a single background check
+    let (state, age, first_check, needs_update) = {
+        if let Ok(mut cache_guard) = cache.lock() {
+            let age = cache_guard.last_check.elapsed();
+            let needs_update = cache_guard.first_check_done && !cache_guard.checking && age >= cache_guard.interval;
+            cache_guard.checking |= needs_update;
+            (cache_guard.state, age, !cache_guard.first_check_done, needs_update)
+        } else {
+            (ServerState::Unreachable, CACHE_DURATION, true, false) // Default to unreachable if we can't get the lock, and trigger update
+        }
+    };
+
+    // If this is the very first check, do it synchronously to get accurate result
+    if first_check {
+        let client = get_client();
+        let config = get_config();
+        let url = format!("{}/api/tags", config.base_url);
+        let runtime = get_runtime();
+
+        let actual_state = runtime.block_on(probe_server(&client, &url));
+
+        // Update cache with first check result
+        if let Ok(mut cache_guard) = cache.lock() {
+            cache_guard.record(actual_state);
+        }
+
+        return (actual_state, Duration::ZERO, false);
+    }
+
+    // If cache is stale, trigger async update
+    if needs_update {
+        update_running_status_async();
+    }
+
+    (state, age, needs_update)
+}
+
+#[lua_function]
+fn ollama_is_running(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let (state, _, _) = check_running();
+        lua.push_boolean(state == ServerState::Ok);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_is_running_detailed(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let (state, age, refreshing) = check_running();
+
+        lua.new_table();
+        lua.push_boolean(state == ServerState::Ok);
+        lua.set_field(-2, lua_string!("running"));
+        lua.push_string(state.as_str());
+        lua.set_field(-2, lua_string!("state"));
+        lua.push_number(age.as_secs_f64());
+        lua.set_field(-2, lua_string!("age"));
+        lua.push_boolean(refreshing);
+        lua.set_field(-2, lua_string!("refreshing"));
+        1
+    }
+}
+
+// Pushes a Lua array of numbers onto the stack
+unsafe fn push_number_array(lua: gmod::lua::State, values: &[f64]) {
+    lua.new_table();
+    for (i, value) in values.iter().enumerate() {
+        lua.push_integer((i + 1) as isize);
+        lua.push_number(*value);
+        lua.set_table(-3);
+    }
+}
+
+#[lua_function]
+fn ollama_abort(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let request_id = lua.check_integer(1) as u64;
+        let notify_done = lua.get_top() >= 2 && lua.get_boolean(2);
+
+        // Cancel the request future, dropping a stream's response mid-body. Results it still
+        // queued (e.g. tokens of the chunk being read) are skipped from here on
+        let done_ref = match get_pending_requests().get_mut(&request_id) {
+            Some(request) => {
+                if let Some(task) = &request.task {
+                    task.abort();
+                }
+                request.cancelled = true;
+                Some(request.done_ref)
+            },
+            None => None,
+        };
+
+        // Either tell the done callback, or drop all callbacks so nothing gets called anymore
+        match done_ref {
+            Some(Some(done_ref)) if notify_done => push_callback(done_ref, request_id, CallbackData::Cancelled),
+            _ => release_request(lua, request_id),
+        }
+        let pending = done_ref.is_some();
+
+        lua.push_boolean(pending);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_register_preset(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let name = lua.check_string(1).to_string();
+
+        // nil removes the preset
+        if lua.is_none(2) || lua.is_nil(2) {
+            PRESETS.lock().unwrap().remove(&name);
+            return 0;
+        }
+
+        lua.check_table(2);
+
+        // Bad values are reported here rather than on every call using the preset
+        read_options(lua, 2);
+        let preset = read_preset_table(lua, 2);
+        PRESETS.lock().unwrap().insert(name, preset);
+        0
+    }
+}
+
+#[lua_function]
+fn ollama_list_presets(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        lua.new_table();
+        for (i, name) in PRESETS.lock().unwrap().keys().enumerate() {
+            lua.push_integer((i + 1) as isize);
+            lua.push_string(name);
+            lua.set_table(-3);
+        }
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_set_enabled(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        // Requests already in flight go on, only new ones get refused
+        ENABLED.store(lua.get_boolean(1), Ordering::Relaxed);
+        0
+    }
+}
+
+#[lua_function]
+fn ollama_shutdown(lua: gmod::lua::State) -> i32 {
+    shutdown(lua);
+    0
+}
+
+#[lua_function]
+fn ollama_abort_all(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        // Same as Abort for every pending request, plus the results they already queued
+        let requests = std::mem::take(&mut *get_pending_requests());
+        clear_callback_queue();
+        DROPPED_CALLBACKS.lock().unwrap().clear();
+        SHARED_REQUESTS.lock().unwrap().clear();
+
+        for request in requests.values() {
+            if let Some(task) = &request.task {
+                task.abort();
+            }
+            for callback_ref in &request.callback_refs {
+                lua.dereference(*callback_ref);
+            }
+        }
+
+        lua.push_integer(requests.len() as isize);
+        1
+    }
+}
+
+// Pushes a JSON value as the matching Lua value, null becomes nil
+unsafe fn push_json(lua: gmod::lua::State, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Null => lua.push_nil(),
+        serde_json::Value::Bool(value) => lua.push_boolean(*value),
+        serde_json::Value::Number(value) => lua.push_number(value.as_f64().unwrap_or_default()),
+        serde_json::Value::String(value) => lua.push_string(value),
+        serde_json::Value::Array(values) => {
+            lua.new_table();
+            for (i, value) in values.iter().enumerate() {
+                lua.push_integer((i + 1) as isize);
+                push_json(lua, value);
+                lua.set_table(-3);
+            }
+        },
+        serde_json::Value::Object(values) => {
+            lua.new_table();
+            for (key, value) in values {
+                lua.push_string(key);
+                push_json(lua, value);
+                lua.set_table(-3);
+            }
+        },
+    }
+}
+
+// Histories are plain message arrays, their metatable only marks them and adds :Append()
+unsafe fn is_history(lua: gmod::lua::State, index: i32) -> bool {
+    lua.get_field(index, lua_string!("__ollama_history"));
+    let is_history = lua.get_boolean(-1);
+    lua.pop();
+    is_history
+}
+
+// Appends a {role, content} message to the table at the given index
+unsafe fn append_message(lua: gmod::lua::State, index: i32, role: &str, content: &str, tool_calls: &[ToolCall]) {
+    let index = if index < 0 { lua.get_top() + index + 1 } else { index };
+
+    lua.push_integer((lua.len(index) + 1) as isize);
+    lua.new_table();
+    lua.push_string(role);
+    lua.set_field(-2, lua_string!("role"));
+    lua.push_string(content);
+    lua.set_field(-2, lua_string!("content"));
+    if !tool_calls.is_empty() {
+        push_tool_calls(lua, tool_calls);
+        lua.set_field(-2, lua_string!("tool_calls"));
+    }
+    lua.set_table(index);
+}
+
+// Adds the reply of a finished chat to the history it was made with
+unsafe fn append_history_reply(lua: gmod::lua::State, history_ref: Option<i32>, role: &str, content: &str, tool_calls: &[ToolCall]) {
+    if let Some(history_ref) = history_ref {
+        lua.from_reference(history_ref);
+        if lua.is_table(-1) {
+            append_message(lua, -1, role, content, tool_calls);
+        }
+        lua.pop();
+    }
+}
+
+#[lua_function]
+fn ollama_new_history(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        lua.new_table();
+        if lua.lua_type(1) == gmod::lua::LUA_TSTRING {
+            let system = lua.check_string(1).to_string();
+            append_message(lua, -1, "system", &system, &[]);
+        }
+
+        if lua.new_metatable(lua_string!("OllamaHistory")) {
+            lua.new_table();
+            lua.push_boolean(true);
+            lua.set_field(-2, lua_string!("__ollama_history"));
+            lua.push_function(ollama_append_message);
+            lua.set_field(-2, lua_string!("Append"));
+            lua.set_field(-2, lua_string!("__index"));
+        }
+        lua.set_metatable(-2);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_append_message(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        if !lua.is_table(1) {
+            lua.error("History must be a table of messages");
+        }
+
+        let role = lua.check_string(2).to_string();
+        let content = lua.check_string(3).to_string();
+        append_message(lua, 1, &role, &content, &[]);
+        0
+    }
+}
+
+// Pushes tool calls as an array of {name, arguments} tables
+unsafe fn push_tool_calls(lua: gmod::lua::State, tool_calls: &[ToolCall]) {
+    lua.new_table();
+    for (i, tool_call) in tool_calls.iter().enumerate() {
+        lua.push_integer((i + 1) as isize);
+        lua.new_table();
+        lua.push_string(&tool_call.function.name);
+        lua.set_field(-2, lua_string!("name"));
+        push_json(lua, &tool_call.function.arguments);
+        lua.set_field(-2, lua_string!("arguments"));
+        lua.set_table(-3);
+    }
+}
+
+// Pushes generation context tokens as an array that can be passed back to Generate
+unsafe fn push_context(lua: gmod::lua::State, context: &[i32]) {
+    lua.new_table();
+    for (i, token) in context.iter().enumerate() {
+        lua.push_integer((i + 1) as isize);
+        lua.push_integer(*token as isize);
+        lua.set_table(-3);
+    }
+}
+
+// Adds the reported metrics to the result table on top of the stack, plus the derived tokens_per_second
+unsafe fn set_metrics_fields(lua: gmod::lua::State, metrics: &GenerationMetrics) {
+    let fields = [
+        (metrics.total_duration, lua_string!("total_duration")),
+        (metrics.load_duration, lua_string!("load_duration")),
+        (metrics.prompt_eval_count.map(u64::from), lua_string!("prompt_eval_count")),
+        (metrics.prompt_eval_duration, lua_string!("prompt_eval_duration")),
+        (metrics.eval_count.map(u64::from), lua_string!("eval_count")),
+        (metrics.eval_duration, lua_string!("eval_duration")),
+    ];
+
+    for (value, key) in fields {
+        if let Some(value) = value {
+            lua.push_number(value as f64);
+            lua.set_field(-2, key);
+        }
+    }
+
+    if let (Some(eval_count), Some(eval_duration)) = (metrics.eval_count, metrics.eval_duration) {
+        if eval_duration > 0 {
+            lua.push_number(eval_count as f64 / (eval_duration as f64 / 1e9));
+            lua.set_field(-2, lua_string!("tokens_per_second"));
+        }
+    }
+}
+
+// With include_metadata, gathers everything the server reported about the generation
+// in a meta table on the result table at the top of the stack
+unsafe fn set_meta_field(lua: gmod::lua::State, metrics: &GenerationMetrics, done_reason: &Option<String>) {
+    if !get_config().include_metadata {
+        return;
+    }
+
+    lua.new_table();
+    set_metrics_fields(lua, metrics);
+    set_done_reason_field(lua, done_reason);
+    if let Some(created_at) = &metrics.created_at {
+        lua.push_string(created_at);
+        lua.set_field(-2, lua_string!("created_at"));
+    }
+    lua.set_field(-2, lua_string!("meta"));
+}
+
+// Sets the reasoning of a thinking model on the result table at the top of the stack,
+// absent when the model didn't reason
+unsafe fn set_thinking_field(lua: gmod::lua::State, thinking: &Option<String>) {
+    if let Some(thinking) = thinking {
+        lua.push_string(thinking);
+        lua.set_field(-2, lua_string!("thinking"));
+    }
+}
+
+// Sets why generation stopped on the result table at the top of the stack, if reported
+unsafe fn set_done_reason_field(lua: gmod::lua::State, done_reason: &Option<String>) {
+    if let Some(done_reason) = done_reason {
+        lua.push_string(done_reason);
+        lua.set_field(-2, lua_string!("done_reason"));
+    }
+}
+
+// Sets the detail fields on the model table at the top of the stack, absent ones stay nil
+unsafe fn set_model_details_fields(lua: gmod::lua::State, details: &Option<ModelDetails>) {
+    let Some(details) = details else {
+        return;
+    };
+
+    let fields = [
+        (&details.format, lua_string!("format")),
+        (&details.family, lua_string!("family")),
+        (&details.parameter_size, lua_string!("parameter_size")),
+        (&details.quantization_level, lua_string!("quantization_level")),
+    ];
+
+    for (value, key) in fields {
+        if let Some(value) = value {
+            lua.push_string(value);
+            lua.set_field(-2, key);
+        }
+    }
+}
+
+// Error tables print and concatenate the way the plain error strings used to
+unsafe fn error_string(lua: gmod::lua::State, index: i32) -> String {
+    if !lua.is_table(index) {
+        return lua.get_string(index).unwrap_or_default().to_string();
+    }
+
+    lua.get_field(index, lua_string!("message"));
+    let message = lua.get_string(-1).unwrap_or_default().to_string();
+    lua.pop();
+    format!("Error: {}", message)
+}
+
+#[lua_function]
+fn error_tostring(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let string = error_string(lua, 1);
+        lua.push_string(&string);
+        1
+    }
+}
+
+#[lua_function]
+fn error_concat(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let string = error_string(lua, 1) + &error_string(lua, 2);
+        lua.push_string(&string);
+        1
+    }
+}
+
+unsafe fn push_error(lua: gmod::lua::State, error: &RequestError) {
+    lua.new_table();
+    lua.push_string(error.kind);
+    lua.set_field(-2, lua_string!("kind"));
+    lua.push_string(&error.message);
+    lua.set_field(-2, lua_string!("message"));
+    if let Some(http_status) = error.http_status {
+        lua.push_integer(http_status as isize);
+        lua.set_field(-2, lua_string!("http_status"));
+    }
+    if let Some(body) = &error.body {
+        lua.push_string(body);
+        lua.set_field(-2, lua_string!("body"));
+    }
+
+    if lua.new_metatable(lua_string!("OllamaError")) {
+        lua.push_function(error_tostring);
+        lua.set_field(-2, lua_string!("__tostring"));
+        lua.push_function(error_concat);
+        lua.set_field(-2, lua_string!("__concat"));
+    }
+    lua.set_metatable(-2);
+}
+
+#[lua_function]
+fn ollama_pending_count(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let pending = get_callback_queue().lock().unwrap().len();
+        lua.push_integer(pending as isize);
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_cosine_similarity(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let a = read_vector(lua, 1);
+        let b = read_vector(lua, 2);
+
+        if a.len() != b.len() {
+            lua.error("Vectors must have the same length");
+        }
+
+        lua.push_number(cosine_similarity(&a, &b));
+        1
+    }
+}
+
+#[lua_function]
+fn ollama_free_embedding(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let handle = lua.check_integer(1) as u64;
+        let freed = EMBEDDING_STORE.lock().unwrap().remove(&handle).is_some();
+        lua.push_boolean(freed);
+        1
+    }
+}
 
-            queue.lock().unwrap().push(callback_result);
-        });
+#[lua_function]
+fn ollama_similarity(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let a = lua.check_integer(1) as u64;
+        let b = lua.check_integer(2) as u64;
+
+        // Worked out under the lock, the error is raised once it's released
+        let similarity = {
+            let store = EMBEDDING_STORE.lock().unwrap();
+            match (store.get(&a), store.get(&b)) {
+                (Some(a), Some(b)) if a.len() == b.len() => Ok(cosine_similarity(a, b)),
+                (Some(_), Some(_)) => Err("Vectors must have the same length".to_string()),
+                (None, _) => Err(format!("Unknown embedding handle {}", a)),
+                (_, None) => Err(format!("Unknown embedding handle {}", b)),
+            }
+        };
 
-        0
+        match similarity {
+            Ok(similarity) => lua.push_number(similarity),
+            Err(message) => lua.error(message),
+        }
+        1
     }
 }
 
 #[lua_function]
-fn ollama_is_running(lua: gmod::lua::State) -> i32 {
+fn ollama_top_k_similar(lua: gmod::lua::State) -> i32 {
     unsafe {
-        let cache = get_running_cache();
+        let query = read_vector(lua, 1);
 
-        let (is_running, needs_update, first_check) = {
-            if let Ok(cache_guard) = cache.lock() {
-                let needs_update = cache_guard.last_check.elapsed() >= CACHE_DURATION;
-                (cache_guard.is_running, needs_update, !cache_guard.first_check_done)
-            } else {
-                (false, true, true) // Default to false if we can't get the lock, and trigger update
-            }
-        };
+        if !lua.is_table(2) {
+            lua.error("Candidates must be a table of vectors");
+        }
 
-        // If this is the very first check, do it synchronously to get accurate result
-        if first_check {
-            let client = get_client().clone();
-            let config = get_config();
-            let url = format!("{}/api/tags", config.base_url);
-            let runtime = get_runtime();
+        // Without k every candidate is ranked
+        let count = lua.len(2).max(0) as usize;
+        let k = if lua.is_none(3) || lua.is_nil(3) { count } else { lua.check_integer(3).max(0) as usize };
 
-            let actual_status = runtime.block_on(async {
-                match client.get(&url).send().await {
-                    Ok(response) => response.status().is_success(),
-                    Err(_) => false,
-                }
-            });
+        let mut scores = Vec::with_capacity(count);
+        for i in 1..=count {
+            lua.raw_geti(2, i as i32);
+            let candidate = read_vector(lua, -1);
+            lua.pop();
 
-            // Update cache with first check result
-            if let Ok(mut cache_guard) = cache.lock() {
-                cache_guard.is_running = actual_status;
-                cache_guard.last_check = Instant::now();
-                cache_guard.first_check_done = true;
+            if candidate.len() != query.len() {
+                lua.error(format!("Candidate {} doesn't have the same length as the query", i));
             }
 
-            lua.push_boolean(actual_status);
-            return 1;
+            scores.push((i, cosine_similarity(&query, &candidate)));
         }
 
-        // If cache is stale, trigger async update
-        if needs_update {
-            update_running_status_async();
-        }
+        scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scores.truncate(k);
 
-        lua.push_boolean(is_running);
+        lua.new_table();
+        for (i, (index, score)) in scores.iter().enumerate() {
+            lua.push_integer((i + 1) as isize);
+            lua.new_table();
+            lua.push_integer(*index as isize);
+            lua.set_field(-2, lua_string!("index"));
+            lua.push_number(*score);
+            lua.set_field(-2, lua_string!("score"));
+            lua.set_table(-3);
+        }
         1
     }
 }
@@ -791,27 +4589,180 @@ fn ollama_is_running(lua: gmod::lua::State) -> i32 {
 #[lua_function]
 fn process_callbacks(lua: gmod::lua::State) -> i32 {
     unsafe {
-        let queue = get_callback_queue();
-        let mut callbacks = queue.lock().unwrap();
+        // Take the pending results out first so callbacks can queue new requests, at most
+        // max_callbacks_per_frame of them, the rest waits for the next Think in order
+        let callbacks: Vec<CallbackResult> = {
+            let queue = get_callback_queue();
+            let mut queue = queue.lock().unwrap();
+            let count = queue.len().min(get_config().max_callbacks_per_frame);
+            queue.drain(..count).collect()
+        };
+        callback_queue_space().notify_waiters();
+
+        // Results that didn't fit in max_queued_callbacks, the request is released if its
+        // final result was among them since nothing else will arrive for it
+        let dropped = std::mem::take(&mut *DROPPED_CALLBACKS.lock().unwrap());
+        for (request_id, final_dropped) in dropped {
+            let cancelled = match get_pending_requests().get(&request_id) {
+                Some(request) => request.cancelled,
+                None => continue,
+            };
+
+            if !cancelled {
+                let error = RequestError::new("queue_overflow", "Results were dropped because the callback queue is full");
+                if log_enabled(LogLevel::Error) {
+                    println!("[gm_ollama] Request {} failed ({}): {}", request_id, error.kind, error.message);
+                }
+                run_result_hook(lua, request_id, Some(&error));
+            }
+
+            if final_dropped {
+                release_request(lua, request_id);
+            }
+        }
+
+        for callback_result in callbacks {
+            // Results of aborted requests can still arrive, their references are already gone
+            // or only the Cancelled notification is left to deliver
+            let cancelled = match get_pending_requests().get(&callback_result.request_id) {
+                Some(request) => request.cancelled,
+                None => continue,
+            };
+            if cancelled && !matches!(callback_result.data, CallbackData::Cancelled) {
+                continue;
+            }
+
+            let is_final = callback_result.data.is_final();
+
+            // Results that waited too long (e.g. after a lag spike) are dropped without being
+            // called, whatever triggered them is likely gone by now
+            if get_config().max_callback_age.is_some_and(|max_age| callback_result.queued_at.elapsed() > max_age) {
+                if is_final {
+                    release_request(lua, callback_result.request_id);
+                }
+                continue;
+            }
+
+            if let CallbackData::Error { error } = &callback_result.data {
+                if log_enabled(LogLevel::Error) {
+                    println!("[gm_ollama] Request {} failed ({}): {}", callback_result.request_id, error.kind, error.message);
+                }
+                run_result_hook(lua, callback_result.request_id, Some(error));
+            } else if is_final && !cancelled {
+                run_result_hook(lua, callback_result.request_id, None);
+            }
 
-        for callback_result in callbacks.drain(..) {
             // Push error handler function that calls ErrorNoHaltWithStack
             lua.get_global(lua_string!("ErrorNoHaltWithStack"));
             let error_handler_index = lua.get_top();
 
             lua.from_reference(callback_result.callback_ref);
 
-            match callback_result.data {
-                CallbackData::Generate { response, model } => {
+            // GenerateAsync/ChatAsync wait in a coroutine, coroutine.resume(thread, ...) gets
+            // the same arguments a callback would
+            let resumes = lua.lua_type(-1) == gmod::lua::LUA_TTHREAD;
+            if resumes {
+                lua.get_global(lua_string!("coroutine"));
+                lua.get_field(-1, lua_string!("resume"));
+                lua.remove(-2);
+                lua.insert(-2);
+            }
+
+            // The reference can outlive the function it pointed to (e.g. after a
+            // lua_refresh), never call whatever ended up there instead
+            if !lua.is_function(-1) && !resumes {
+                if get_config().warn_invalid_callbacks {
+                    println!("[gm_ollama] Skipping callback {}: reference no longer points to a function", callback_result.callback_ref);
+                }
+
+                lua.pop_n(2); // Pop the invalid value and the error handler
+                if is_final {
+                    release_request(lua, callback_result.request_id);
+                }
+                continue;
+            }
+
+            // A caller supplied id goes in front of all other arguments
+            let (tag, history_ref) = get_pending_requests().get(&callback_result.request_id)
+                .map(|request| (request.tag.clone(), request.history_ref))
+                .unwrap_or_default();
+            if let Some(tag) = &tag {
+                push_json(lua, tag);
+            }
+
+            // Chunk timings go after the request id, where they don't shift existing arguments
+            let timing = match &callback_result.data {
+                CallbackData::GenerateToken { index, delta_ms, .. } | CallbackData::ChatDelta { index, delta_ms, .. } => Some((*index, *delta_ms)),
+                _ => None,
+            };
+
+            let nargs = match callback_result.data {
+                CallbackData::Generate { response, model, context, thinking, done_reason, metrics } => {
                     lua.push_nil(); // No error
                     lua.new_table();
                     lua.push_string(&response);
                     lua.set_field(-2, lua_string!("response"));
                     lua.push_string(&model);
                     lua.set_field(-2, lua_string!("model"));
-                    let _ = lua.pcall(2, 0, error_handler_index);
+                    push_context(lua, &context);
+                    lua.set_field(-2, lua_string!("context"));
+                    set_thinking_field(lua, &thinking);
+                    set_done_reason_field(lua, &done_reason);
+                    set_metrics_fields(lua, &metrics);
+                    set_meta_field(lua, &metrics, &done_reason);
+                    2
+                },
+                CallbackData::GenerateToken { token, .. } => {
+                    lua.push_string(&token);
+                    1
+                },
+                CallbackData::GenerateDone { response, model, context, thinking, done_reason, metrics, output_file } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    match output_file {
+                        Some(output_file) => {
+                            lua.push_string(&output_file);
+                            lua.set_field(-2, lua_string!("output_file"));
+                        },
+                        None => {
+                            lua.push_string(&response);
+                            lua.set_field(-2, lua_string!("response"));
+                        },
+                    }
+                    lua.push_string(&model);
+                    lua.set_field(-2, lua_string!("model"));
+                    push_context(lua, &context);
+                    lua.set_field(-2, lua_string!("context"));
+                    set_thinking_field(lua, &thinking);
+                    set_done_reason_field(lua, &done_reason);
+                    set_metrics_fields(lua, &metrics);
+                    set_meta_field(lua, &metrics, &done_reason);
+                    2
+                },
+                CallbackData::Chat { content, role, model, tool_calls, thinking, done_reason, metrics } => {
+                    append_history_reply(lua, history_ref, &role, &content, &tool_calls);
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_string(&content);
+                    lua.set_field(-2, lua_string!("content"));
+                    lua.push_string(&role);
+                    lua.set_field(-2, lua_string!("role"));
+                    lua.push_string(&model);
+                    lua.set_field(-2, lua_string!("model"));
+                    push_tool_calls(lua, &tool_calls);
+                    lua.set_field(-2, lua_string!("tool_calls"));
+                    set_thinking_field(lua, &thinking);
+                    set_done_reason_field(lua, &done_reason);
+                    set_metrics_fields(lua, &metrics);
+                    set_meta_field(lua, &metrics, &done_reason);
+                    2
                 },
-                CallbackData::Chat { content, role, model } => {
+                CallbackData::ChatDelta { content, .. } => {
+                    lua.push_string(&content);
+                    1
+                },
+                CallbackData::ChatDone { content, role, model, tool_calls, thinking, done_reason, metrics } => {
+                    append_history_reply(lua, history_ref, &role, &content, &tool_calls);
                     lua.push_nil(); // No error
                     lua.new_table();
                     lua.push_string(&content);
@@ -820,7 +4771,13 @@ fn process_callbacks(lua: gmod::lua::State) -> i32 {
                     lua.set_field(-2, lua_string!("role"));
                     lua.push_string(&model);
                     lua.set_field(-2, lua_string!("model"));
-                    let _ = lua.pcall(2, 0, error_handler_index);
+                    push_tool_calls(lua, &tool_calls);
+                    lua.set_field(-2, lua_string!("tool_calls"));
+                    set_thinking_field(lua, &thinking);
+                    set_done_reason_field(lua, &done_reason);
+                    set_metrics_fields(lua, &metrics);
+                    set_meta_field(lua, &metrics, &done_reason);
+                    2
                 },
                 CallbackData::ListModels { models } => {
                     lua.push_nil(); // No error
@@ -835,17 +4792,24 @@ fn process_callbacks(lua: gmod::lua::State) -> i32 {
                         lua.push_string(&model.modified_at);
                         lua.set_field(-2, lua_string!("modified_at"));
 
+                        if let Some(modified_at_unix) = parse_rfc3339(&model.modified_at) {
+                            lua.push_number(modified_at_unix as f64);
+                            lua.set_field(-2, lua_string!("modified_at_unix"));
+                        }
+
                         lua.push_number(model.size as f64);
                         lua.set_field(-2, lua_string!("size"));
 
                         lua.push_string(&model.digest);
                         lua.set_field(-2, lua_string!("digest"));
 
+                        set_model_details_fields(lua, &model.details);
+
                         lua.set_table(-3);
                     }
-                    let _ = lua.pcall(2, 0, error_handler_index);
+                    2
                 },
-                CallbackData::GetModelInfo { license, modelfile, parameters, template } => {
+                CallbackData::GetModelInfo { license, modelfile, parameters, template, details } => {
                     lua.push_nil(); // No error
                     lua.new_table();
                     lua.push_string(&license);
@@ -856,34 +4820,136 @@ fn process_callbacks(lua: gmod::lua::State) -> i32 {
                     lua.set_field(-2, lua_string!("parameters"));
                     lua.push_string(&template);
                     lua.set_field(-2, lua_string!("template"));
-                    let _ = lua.pcall(2, 0, error_handler_index);
+                    set_model_details_fields(lua, &details);
+                    2
                 },
                 CallbackData::IsModelAvailable { is_available } => {
                     lua.push_nil(); // No error
                     lua.push_boolean(is_available);
-                    let _ = lua.pcall(2, 0, error_handler_index);
+                    2
+                },
+                CallbackData::Version { version } => {
+                    lua.push_nil(); // No error
+                    lua.push_string(&version);
+                    2
+                },
+                CallbackData::RawResponse { body } => {
+                    lua.push_nil(); // No error
+                    lua.push_string(&body);
+                    2
                 },
-                CallbackData::Embeddings { model, embeddings } => {
+                CallbackData::CountTokens { model, tokens } => {
                     lua.push_nil(); // No error
                     lua.new_table();
                     lua.push_string(&model);
                     lua.set_field(-2, lua_string!("model"));
+                    lua.push_number(tokens as f64);
+                    lua.set_field(-2, lua_string!("tokens"));
+                    2
+                },
+                CallbackData::Diagnose { state, http_status, latency_ms, headers, error } => {
+                    lua.push_nil(); // No error, failing to reach the server is part of the result
+                    lua.new_table();
+                    lua.push_boolean(http_status.is_some());
+                    lua.set_field(-2, lua_string!("reachable"));
+                    lua.push_string(state.as_str());
+                    lua.set_field(-2, lua_string!("state"));
+                    lua.push_number(latency_ms);
+                    lua.set_field(-2, lua_string!("latency_ms"));
+
+                    if let Some(http_status) = http_status {
+                        lua.push_integer(http_status as isize);
+                        lua.set_field(-2, lua_string!("http_status"));
+                    }
 
-                    // Create embeddings array
                     lua.new_table();
-                    for (i, embedding) in embeddings.iter().enumerate() {
-                        lua.push_integer((i + 1) as isize);
+                    for (name, value) in &headers {
+                        lua.push_string(name);
+                        lua.push_string(value);
+                        lua.set_table(-3);
+                    }
+                    lua.set_field(-2, lua_string!("headers"));
+
+                    if let Some(error) = error {
+                        lua.push_string(error.kind);
+                        lua.set_field(-2, lua_string!("error_kind"));
+                        lua.push_string(&error.message);
+                        lua.set_field(-2, lua_string!("error"));
+                    }
+                    2
+                },
+                CallbackData::Ping { latency_ms } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_boolean(true);
+                    lua.set_field(-2, lua_string!("reachable"));
+                    lua.push_number(latency_ms);
+                    lua.set_field(-2, lua_string!("latency_ms"));
+                    2
+                },
+                CallbackData::Embeddings { model, embeddings, include_vectors, store_handles, input_was_batch } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_string(&model);
+                    lua.set_field(-2, lua_string!("model"));
+                    lua.push_integer(embeddings.len() as isize);
+                    lua.set_field(-2, lua_string!("num_embeddings"));
+                    lua.push_integer(embeddings.first().map_or(0, Vec::len) as isize);
+                    lua.set_field(-2, lua_string!("dimensions"));
+
+                    // Create embeddings array, large vectors are costly to build as tables
+                    // so they can be left out when only the metadata is needed
+                    if store_handles {
+                        let handles = store_embeddings(embeddings);
+
                         lua.new_table();
-                        for (j, value) in embedding.iter().enumerate() {
-                            lua.push_integer((j + 1) as isize);
-                            lua.push_number(*value);
+                        for (i, handle) in handles.iter().enumerate() {
+                            lua.push_integer((i + 1) as isize);
+                            lua.push_integer(*handle as isize);
                             lua.set_table(-3);
                         }
-                        lua.set_table(-3);
+                        lua.set_field(-2, lua_string!("handles"));
+
+                        if let (false, Some(handle)) = (input_was_batch, handles.first()) {
+                            lua.push_integer(*handle as isize);
+                            lua.set_field(-2, lua_string!("handle"));
+                        }
+                    } else if include_vectors {
+                        lua.new_table();
+                        for (i, embedding) in embeddings.iter().enumerate() {
+                            lua.push_integer((i + 1) as isize);
+                            push_number_array(lua, embedding);
+                            lua.set_table(-3);
+                        }
+                        lua.set_field(-2, lua_string!("embeddings"));
+
+                        // A single string input also gets its vector directly
+                        if let (false, Some(embedding)) = (input_was_batch, embeddings.first()) {
+                            push_number_array(lua, embedding);
+                            lua.set_field(-2, lua_string!("embedding"));
+                        }
                     }
-                    lua.set_field(-2, lua_string!("embeddings"));
+                    lua.push_boolean(input_was_batch);
+                    lua.set_field(-2, lua_string!("input_was_batch"));
+
+                    2
+                },
+                CallbackData::EmbedDocument { chunks, embeddings } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    for (i, (chunk, embedding)) in chunks.iter().zip(embeddings.iter()).enumerate() {
+                        lua.push_integer((i + 1) as isize);
+                        lua.new_table();
+
+                        lua.push_string(chunk);
+                        lua.set_field(-2, lua_string!("text"));
 
-                    let _ = lua.pcall(2, 0, error_handler_index);
+                        push_number_array(lua, embedding);
+                        lua.set_field(-2, lua_string!("embedding"));
+
+                        lua.set_table(-3);
+                    }
+                    2
                 },
                 CallbackData::GetRunningModels { models } => {
                     lua.push_nil(); // No error
@@ -907,6 +4973,17 @@ fn process_callbacks(lua: gmod::lua::State) -> i32 {
                         if let Some(expires_at) = &model.expires_at {
                             lua.push_string(expires_at);
                             lua.set_field(-2, lua_string!("expires_at"));
+
+                            // Also as numbers so countdowns don't need to parse the timestamp
+                            if let Some(expires_at_unix) = parse_rfc3339(expires_at) {
+                                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64;
+
+                                lua.push_number(expires_at_unix as f64);
+                                lua.set_field(-2, lua_string!("expires_at_unix"));
+
+                                lua.push_number((expires_at_unix - now).max(0) as f64);
+                                lua.set_field(-2, lua_string!("expires_in_seconds"));
+                            }
                         }
 
                         if let Some(size_vram) = model.size_vram {
@@ -916,56 +4993,229 @@ fn process_callbacks(lua: gmod::lua::State) -> i32 {
 
                         lua.set_table(-3);
                     }
-                    let _ = lua.pcall(2, 0, error_handler_index);
+                    2
+                },
+                CallbackData::PullProgress { status, digest, completed, total } => {
+                    lua.new_table();
+                    lua.push_string(&status);
+                    lua.set_field(-2, lua_string!("status"));
+
+                    if let Some(digest) = digest {
+                        lua.push_string(&digest);
+                        lua.set_field(-2, lua_string!("digest"));
+                    }
+
+                    if let Some(completed) = completed {
+                        lua.push_number(completed as f64);
+                        lua.set_field(-2, lua_string!("completed"));
+                    }
+
+                    if let Some(total) = total {
+                        lua.push_number(total as f64);
+                        lua.set_field(-2, lua_string!("total"));
+                    }
+                    1
+                },
+                CallbackData::PullDone { model, status } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_string(&model);
+                    lua.set_field(-2, lua_string!("model"));
+                    lua.push_string(&status);
+                    lua.set_field(-2, lua_string!("status"));
+                    2
+                },
+                CallbackData::DeleteModel { model } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_string(&model);
+                    lua.set_field(-2, lua_string!("model"));
+                    2
+                },
+                CallbackData::UnloadModel { model } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_string(&model);
+                    lua.set_field(-2, lua_string!("model"));
+                    2
+                },
+                CallbackData::Preload { model, load_duration } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_string(&model);
+                    lua.set_field(-2, lua_string!("model"));
+
+                    // Close to 0 when the model was loaded already
+                    if let Some(load_duration) = load_duration {
+                        lua.push_number(load_duration as f64);
+                        lua.set_field(-2, lua_string!("load_duration"));
+                    }
+                    2
+                },
+                CallbackData::CopyModel { source, destination } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_string(&source);
+                    lua.set_field(-2, lua_string!("source"));
+                    lua.push_string(&destination);
+                    lua.set_field(-2, lua_string!("destination"));
+                    2
+                },
+                CallbackData::DryRun { method, url, body } => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_boolean(true);
+                    lua.set_field(-2, lua_string!("dry_run"));
+                    lua.push_string(&method);
+                    lua.set_field(-2, lua_string!("method"));
+                    lua.push_string(&url);
+                    lua.set_field(-2, lua_string!("url"));
+                    lua.push_string(&body);
+                    lua.set_field(-2, lua_string!("body"));
+                    2
+                },
+                CallbackData::Cancelled => {
+                    lua.push_nil(); // No error
+                    lua.new_table();
+                    lua.push_boolean(true);
+                    lua.set_field(-2, lua_string!("was_cancelled"));
+                    2
                 },
-                CallbackData::Error { message } => {
-                    lua.push_string(&message); // Error message
+                CallbackData::Error { error } => {
+                    push_error(lua, &error);
                     lua.push_nil();
-                    let _ = lua.pcall(2, 0, error_handler_index);
+                    2
                 },
+            };
+
+            // Every callback also receives the id returned when the request was made
+            lua.push_integer(callback_result.request_id as isize);
+            if let Some((index, delta_ms)) = timing {
+                lua.push_integer(index as isize);
+                lua.push_number(delta_ms);
+            }
+            let nargs = nargs + 1 + tag.is_some() as i32 + if timing.is_some() { 2 } else { 0 };
+            if resumes {
+                // Errors inside the coroutine come back from resume instead of being raised
+                if lua.pcall(nargs + 1, 2, error_handler_index) == 0 {
+                    if !lua.get_boolean(-2) {
+                        let message = lua.get_string(-1).unwrap_or_default().to_string();
+                        lua.get_global(lua_string!("ErrorNoHalt"));
+                        lua.push_string(&format!("{}\n", message));
+                        let _ = lua.pcall(1, 0, 0);
+                    }
+                    lua.pop_n(2);
+                }
+            } else {
+                let _ = lua.pcall(nargs, 0, error_handler_index);
             }
 
             // Clean up error handler from stack
             lua.pop();
 
-            lua.dereference(callback_result.callback_ref);
+            if is_final {
+                release_request(lua, callback_result.request_id);
+            }
         }
 
         0
     }
 }
 
+// Runs OllamaError(message, kind, request_id) or OllamaSuccess(request_id) for whoever
+// wants to watch every request in one place, besides the request's own callback
+unsafe fn run_result_hook(lua: gmod::lua::State, request_id: u64, error: Option<&RequestError>) {
+    lua.get_global(lua_string!("ErrorNoHaltWithStack"));
+    let error_handler_index = lua.get_top();
+
+    lua.get_global(lua_string!("hook"));
+        lua.get_field(-1, lua_string!("Run"));
+            let nargs = match error {
+                Some(error) => {
+                    lua.push_string("OllamaError");
+                    lua.push_string(&error.message);
+                    lua.push_string(error.kind);
+                    4
+                },
+                None => {
+                    lua.push_string("OllamaSuccess");
+                    2
+                },
+            };
+            lua.push_integer(request_id as isize);
+        let _ = lua.pcall(nargs, 0, error_handler_index);
+    lua.pop_n(2); // Pop the hook table and the error handler
+}
+
+// Event and identifier of the hook currently running process_callbacks
+static CALLBACK_HOOK: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+unsafe fn add_callback_hook(lua: gmod::lua::State, event: &str, name: &str) {
+    lua.get_global(lua_string!("hook"));
+        lua.get_field(-1, lua_string!("Add"));
+            lua.push_string(event);
+            lua.push_string(name);
+            lua.push_function(process_callbacks);
+        lua.call(3, 0);
+    lua.pop();
+
+    *CALLBACK_HOOK.lock().unwrap() = Some((event.to_string(), name.to_string()));
+}
+
+unsafe fn remove_callback_hook(lua: gmod::lua::State) {
+    let Some((event, name)) = CALLBACK_HOOK.lock().unwrap().take() else {
+        return;
+    };
+
+    lua.get_global(lua_string!("hook"));
+        lua.get_field(-1, lua_string!("Remove"));
+            lua.push_string(&event);
+            lua.push_string(&name);
+        lua.call(2, 0);
+    lua.pop();
+}
+
+// Moves callback processing to another hook event, e.g. "Tick" on gamemodes that load Think
+#[lua_function]
+fn ollama_set_callback_hook(lua: gmod::lua::State) -> i32 {
+    unsafe {
+        let event = lua.check_string(1).to_string();
+        let name = if lua.is_none(2) || lua.is_nil(2) {
+            "__OllamaCallbacks".to_string()
+        } else {
+            lua.check_string(2).to_string()
+        };
+
+        remove_callback_hook(lua);
+        add_callback_hook(lua, &event, &name);
+        0
+    }
+}
+
 fn initialize_callback_processor(lua: gmod::lua::State) {
     unsafe {
-        lua.get_global(lua_string!("hook"));
-            lua.get_field(-1, lua_string!("Add"));
-                lua.push_string("Think");
-                lua.push_string("__OllamaCallbacks");
-                lua.push_function(process_callbacks);
-            lua.call(3, 0);
-        lua.pop_n(2);
+        add_callback_hook(lua, "Think", "__OllamaCallbacks");
     }
 }
 
 fn finish_callback_processor(lua: gmod::lua::State) {
     unsafe {
-        lua.get_global(lua_string!("hook"));
-            lua.get_field(-1, lua_string!("Remove"));
-                lua.push_string("Think");
-                lua.push_string("__OllamaCallbacks");
-            lua.call(2, 0);
-        lua.pop_n(2);
-
-        // Clear callback queue and dereference all Lua callbacks
-        let ptr = std::ptr::addr_of_mut!(CALLBACK_QUEUE);
-        if let Some(queue) = (*ptr).as_ref() {
-            if let Ok(mut callbacks) = queue.lock() {
-                for callback_result in callbacks.drain(..) {
-                    lua.dereference(callback_result.callback_ref);
-                }
+        remove_callback_hook(lua);
+
+        // Stop outstanding requests and dereference all Lua callbacks they still hold
+        let pending_requests = std::mem::take(&mut *get_pending_requests());
+        for (_, request) in pending_requests {
+            if let Some(task) = request.task {
+                task.abort();
+            }
+
+            for callback_ref in request.callback_refs {
+                lua.dereference(callback_ref);
             }
         }
 
+        // Drop whatever they queued before being stopped, it will never run
+        clear_callback_queue();
     }
 }
 
@@ -984,15 +5234,85 @@ fn gmod13_open(lua: gmod::lua::State) -> i32 {
         lua.push_function(ollama_generate);
         lua.set_field(-2, lua_string!("Generate"));
 
+        lua.push_function(ollama_generate_async);
+        lua.set_field(-2, lua_string!("GenerateAsync"));
+
+        lua.push_function(ollama_generate_stream);
+        lua.set_field(-2, lua_string!("GenerateStream"));
+
+        lua.push_function(ollama_count_tokens);
+        lua.set_field(-2, lua_string!("CountTokens"));
+
         lua.push_function(ollama_chat);
         lua.set_field(-2, lua_string!("Chat"));
 
+        lua.push_function(ollama_chat_async);
+        lua.set_field(-2, lua_string!("ChatAsync"));
+
+        lua.push_function(ollama_chat_stream);
+        lua.set_field(-2, lua_string!("ChatStream"));
+
         lua.push_function(ollama_list_models);
         lua.set_field(-2, lua_string!("ListModels"));
 
+        lua.push_function(ollama_pull_model);
+        lua.set_field(-2, lua_string!("PullModel"));
+
+        lua.push_function(ollama_delete_model);
+        lua.set_field(-2, lua_string!("DeleteModel"));
+
+        lua.push_function(ollama_copy_model);
+        lua.set_field(-2, lua_string!("CopyModel"));
+
+        lua.push_function(ollama_unload_model);
+        lua.set_field(-2, lua_string!("UnloadModel"));
+
+        lua.push_function(ollama_preload);
+        lua.set_field(-2, lua_string!("Preload"));
+
         lua.push_function(ollama_is_running);
         lua.set_field(-2, lua_string!("IsRunning"));
 
+        lua.push_function(ollama_is_running_detailed);
+        lua.set_field(-2, lua_string!("IsRunningDetailed"));
+
+        lua.push_function(ollama_ping);
+        lua.set_field(-2, lua_string!("Ping"));
+
+        lua.push_function(ollama_diagnose);
+        lua.set_field(-2, lua_string!("Diagnose"));
+
+        lua.push_function(ollama_get_version);
+        lua.set_field(-2, lua_string!("GetVersion"));
+
+        lua.push_function(ollama_abort);
+        lua.set_field(-2, lua_string!("Abort"));
+
+        lua.push_function(ollama_abort_all);
+        lua.set_field(-2, lua_string!("AbortAll"));
+
+        lua.push_function(ollama_register_preset);
+        lua.set_field(-2, lua_string!("RegisterPreset"));
+
+        lua.push_function(ollama_list_presets);
+        lua.set_field(-2, lua_string!("ListPresets"));
+
+        lua.push_function(ollama_set_enabled);
+        lua.set_field(-2, lua_string!("SetEnabled"));
+
+        lua.push_function(ollama_shutdown);
+        lua.set_field(-2, lua_string!("Shutdown"));
+
+        lua.push_function(ollama_set_callback_hook);
+        lua.set_field(-2, lua_string!("SetCallbackHook"));
+
+        lua.push_function(ollama_pending_count);
+        lua.set_field(-2, lua_string!("PendingCount"));
+
+        // Same function the hook runs, for draining the queue outside of it
+        lua.push_function(process_callbacks);
+        lua.set_field(-2, lua_string!("ProcessCallbacks"));
+
         lua.push_function(ollama_get_model_info);
         lua.set_field(-2, lua_string!("GetModelInfo"));
 
@@ -1002,6 +5322,27 @@ fn gmod13_open(lua: gmod::lua::State) -> i32 {
         lua.push_function(ollama_generate_embeddings);
         lua.set_field(-2, lua_string!("GenerateEmbeddings"));
 
+        lua.push_function(ollama_embed_document);
+        lua.set_field(-2, lua_string!("EmbedDocument"));
+
+        lua.push_function(ollama_new_history);
+        lua.set_field(-2, lua_string!("NewHistory"));
+
+        lua.push_function(ollama_append_message);
+        lua.set_field(-2, lua_string!("AppendMessage"));
+
+        lua.push_function(ollama_cosine_similarity);
+        lua.set_field(-2, lua_string!("CosineSimilarity"));
+
+        lua.push_function(ollama_similarity);
+        lua.set_field(-2, lua_string!("Similarity"));
+
+        lua.push_function(ollama_free_embedding);
+        lua.set_field(-2, lua_string!("FreeEmbedding"));
+
+        lua.push_function(ollama_top_k_similar);
+        lua.set_field(-2, lua_string!("TopKSimilar"));
+
         lua.push_function(ollama_get_running_models);
         lua.set_field(-2, lua_string!("GetRunningModels"));
 
@@ -1012,23 +5353,196 @@ fn gmod13_open(lua: gmod::lua::State) -> i32 {
     }
 }
 
+// Stops everything the module runs and resets all globals to how they were before
+// gmod13_open, so nothing from before leaks into whatever comes next
+fn shutdown(lua: gmod::lua::State) {
+    finish_callback_processor(lua);
+
+    // Shut down the Tokio runtime: cancels in-flight tasks at their await
+    // points and joins all worker threads, so no module code can still be
+    // running when GMod unloads the DLL
+    let runtime = RUNTIME.lock().unwrap().take();
+    if let Some(runtime) = runtime {
+        runtime.shutdown_timeout(Duration::from_secs(1));
+    }
+
+    *CLIENT.lock().unwrap() = None;
+    *REQUEST_LIMIT.lock().unwrap() = None;
+    RATE_BUCKETS.lock().unwrap().clear();
+    SHARED_REQUESTS.lock().unwrap().clear();
+    clear_callback_queue();
+    DROPPED_CALLBACKS.lock().unwrap().clear();
+    *RUNNING_CACHE.lock().unwrap() = None;
+    get_pending_requests().clear();
+    PRESETS.lock().unwrap().clear();
+    EMBEDDING_STORE.lock().unwrap().clear();
+    *CONFIG.lock().unwrap() = None;
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
 #[gmod13_close]
 fn gmod13_close(lua: gmod::lua::State) -> i32 {
-    finish_callback_processor(lua);
+    shutdown(lua);
+    0
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    unsafe {
-        // Shut down the Tokio runtime: cancels in-flight tasks at their await
-        // points and joins all worker threads, so no module code can still be
-        // running when GMod unloads the DLL
-        if let Some(runtime) = (*std::ptr::addr_of_mut!(RUNTIME)).take() {
-            runtime.shutdown_timeout(Duration::from_secs(1));
+    #[test]
+    fn embedded_error_in_successful_response() {
+        let body = serde_json::from_str::<serde_json::Value>(r#"{"error":"model requires more system memory"}"#).unwrap();
+        assert_eq!(embedded_error(&body).as_deref(), Some("model requires more system memory"));
+
+        let body = serde_json::from_str::<serde_json::Value>(r#"{"model":"llama3","response":"hi","done":true}"#).unwrap();
+        assert_eq!(embedded_error(&body), None);
+    }
+
+    // Only the Rust side up to the String handed to push_string, pushing it needs a live Lua
+    // state and isn't covered here
+    #[test]
+    fn streamed_text_keeps_nul_and_emoji_until_pushed() {
+        let line = "{\"model\":\"llama3\",\"created_at\":\"2024-05-01T12:30:45Z\",\"response\":\"a\\u0000b\u{1F600}\",\"done\":false}\n";
+
+        // Split inside the emoji's 4 bytes
+        let split = line.find('\u{1F600}').unwrap() + 2;
+        let mut lines = LineBuffer::default();
+        lines.push(&line.as_bytes()[..split]);
+        assert_eq!(lines.next_line(), None);
+        lines.push(&line.as_bytes()[split..]);
+
+        let chunk = serde_json::from_str::<GenerateResponse>(&lines.next_line().unwrap()).unwrap();
+        assert_eq!(chunk.response, "a\0b\u{1F600}");
+        assert_eq!(chunk.response.len(), 7);
+    }
+
+    #[test]
+    fn line_buffer_joins_lines_split_across_chunks() {
+        let mut lines = LineBuffer::default();
+        lines.push(b"{\"a\":");
+        assert_eq!(lines.next_line(), None);
+
+        lines.push(b"1}\n\n{\"b\"");
+        assert_eq!(lines.next_line().as_deref(), Some("{\"a\":1}"));
+        assert_eq!(lines.next_line(), None);
+
+        lines.push(b":2}");
+        assert_eq!(lines.next_line(), None);
+        assert_eq!(lines.finish().as_deref(), Some("{\"b\":2}"));
+        assert_eq!(lines.finish(), None);
+    }
+
+    #[test]
+    fn parse_rfc3339_timestamps() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2024-05-01T12:30:45.123456789-07:00"), Some(1714591845));
+        assert_eq!(parse_rfc3339("2024-02-29t00:00:00+02:00"), Some(1709157600));
+        assert_eq!(parse_rfc3339("2024-05-01T12:30:45"), None);
+        assert_eq!(parse_rfc3339("2024-13-01T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2024-05-01T12:30:45+0700"), None);
+        assert_eq!(parse_rfc3339("not a timestamp at all"), None);
+    }
+
+    #[test]
+    fn chunk_text_overlaps_chunks() {
+        assert_eq!(chunk_text("abcdefghij", 4, 1), vec!["abcd", "defg", "ghij"]);
+        assert_eq!(chunk_text("h\u{e9}llo w\u{f6}rld", 5, 0), vec!["h\u{e9}llo", " w\u{f6}rl", "d"]);
+        assert_eq!(chunk_text("short", 10, 2), vec!["short"]);
+        assert!(chunk_text("", 10, 2).is_empty());
+    }
+
+    #[test]
+    fn model_names() {
+        for name in ["llama3", "llama3:8b", "user/model:q4_K_M", "registry.ollama.ai/library/llama3:latest"] {
+            assert!(is_valid_model_name(name), "{}", name);
+        }
+
+        let too_long = "a".repeat(257);
+        for name in ["", ".hidden", "-model", "model:", "bad name", "a/b/c/d", "llama3@sha256:abc", too_long.as_str()] {
+            assert!(!is_valid_model_name(name), "{}", name);
         }
+    }
 
-        *std::ptr::addr_of_mut!(CLIENT) = None;
-        *std::ptr::addr_of_mut!(CALLBACK_QUEUE) = None;
-        *std::ptr::addr_of_mut!(RUNNING_CACHE) = None;
-        *std::ptr::addr_of_mut!(CONFIG) = None;
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(&[0xff, 0xfe]), "//4=");
+    }
 
-        0
+    #[test]
+    fn classify_health_checks() {
+        assert_eq!(classify_probe(200, r#"{"models":[]}"#), ServerState::Ok);
+        assert_eq!(classify_probe(200, "<html>It works!</html>"), ServerState::NotOllama);
+        assert_eq!(classify_probe(404, "404 page not found"), ServerState::NotOllama);
+        assert_eq!(classify_probe(404, "<html><body>Not Found</body></html>"), ServerState::NotOllama);
+        assert_eq!(classify_probe(500, r#"{"error":"something went wrong"}"#), ServerState::ServerError);
+        assert_eq!(classify_probe(502, "<html>Bad Gateway</html>"), ServerState::ServerError);
+        assert_eq!(classify_probe(401, ""), ServerState::Unauthorized);
+        assert_eq!(classify_probe(403, r#"{"models":[]}"#), ServerState::Unauthorized);
+    }
+
+    #[test]
+    fn shared_request_aborted_before_first_poll() {
+        let key = "test:aborted before first poll".to_string();
+        let owner = claim_shared_request(key.clone(), 1, 9001).unwrap();
+        assert!(claim_shared_request(key.clone(), 2, 9002).is_none());
+
+        // Like an aborted task that never got its permit, dropped without being polled
+        let task = async move {
+            let _owner = owner;
+        };
+        drop(task);
+
+        assert!(!SHARED_REQUESTS.lock().unwrap().contains_key(&key));
+        let mut queue = get_callback_queue().lock().unwrap();
+        let position = queue.iter().position(|result| result.request_id == 9002).unwrap();
+        assert!(matches!(queue.remove(position).unwrap().data, CallbackData::Error { .. }));
+    }
+
+    #[test]
+    fn base64_detection() {
+        assert!(is_base64(&base64_encode(b"\x89PNG\r\n\x1a\n")));
+        assert!(is_base64("Zm9vYmFy"));
+        assert!(is_base64("Zg=="));
+        assert!(!is_base64(""));
+        assert!(!is_base64("Zg="));
+        assert!(!is_base64("Zg==="));
+        assert!(!is_base64("not base64!"));
+    }
+
+    #[test]
+    fn empty_list_fields() {
+        assert_eq!(json_child_place(JsonPlace::Top, "stop"), (JsonPlace::Other, true));
+        assert_eq!(json_child_place(JsonPlace::Top, "temperature"), (JsonPlace::Other, false));
+
+        // tools = {{name = ..., parameters = {type = "object", required = {}, properties = {stop = {}}}}}
+        let (tools, is_list) = json_child_place(JsonPlace::Top, "tools");
+        assert!(is_list);
+        let (tool, _) = json_child_place(tools, "1");
+        let (parameters, _) = json_child_place(tool, "parameters");
+        assert_eq!(parameters, JsonPlace::Schema);
+        assert_eq!(json_child_place(parameters, "required"), (JsonPlace::Other, true));
+
+        let (properties, _) = json_child_place(parameters, "properties");
+        assert_eq!(json_child_place(properties, "stop"), (JsonPlace::Schema, false));
+        assert_eq!(json_child_place(properties, "enum"), (JsonPlace::Schema, false));
+
+        // A property schema's own keywords are lists again
+        let (property, _) = json_child_place(properties, "mood");
+        assert_eq!(json_child_place(property, "enum"), (JsonPlace::Other, true));
+        let (format, _) = json_child_place(JsonPlace::Top, "format");
+        assert_eq!(json_child_place(format, "images"), (JsonPlace::Other, false));
+    }
+
+    #[test]
+    fn cosine_similarity_bounds() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-9);
+        assert!((cosine_similarity(&[1.0, 2.0], &[-1.0, -2.0]) + 1.0).abs() < 1e-9);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
     }
-}
\ No newline at end of file
+}